@@ -100,6 +100,20 @@ pub fn init_heap(regions: &MemoryRegions) {
     }
 }
 
+/// The kernel tests don't map a heap, they get a small one from the kernel image
+#[cfg(test)]
+pub fn init_test_heap() {
+    const TEST_HEAP_SIZE: usize = 256 * 1024;
+    #[repr(align(4096))]
+    struct TestHeap([u8; TEST_HEAP_SIZE]);
+    static mut TEST_HEAP: TestHeap = TestHeap([0; TEST_HEAP_SIZE]);
+
+    unsafe {
+        let start = core::ptr::addr_of_mut!(TEST_HEAP) as u64;
+        ALLOCATOR.lock().init(start, TEST_HEAP_SIZE as u64);
+    }
+}
+
 fn align_up(addr: usize, align: usize) -> usize {
     (addr + align - 1) & !(align - 1)
 }
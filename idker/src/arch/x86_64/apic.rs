@@ -1,5 +1,7 @@
 use crate::println;
 use raw_cpuid::CpuId;
+
+use super::{pit, tsc};
 use x86_64::{registers::model_specific::Msr, VirtAddr};
 
 const MSR_IA32_APIC_BASE: Msr = Msr::new(0x1B);
@@ -11,6 +13,10 @@ const MSR_IA32_X2APIC_VERSION: Msr = Msr::new(0x803);
 const MSR_IA32_X2APIC_ICR: Msr = Msr::new(0x830);
 // x2APIC Spurious Interrupt Vector Register
 const MSR_IA32_X2APIC_SIVR: Msr = Msr::new(0x80f);
+// Deadline of the timer in TSC-deadline mode (writing 0 disarms it)
+const MSR_IA32_TSC_DEADLINE: Msr = Msr::new(0x6E0);
+// In x2APIC mode every register is an MSR starting from here (MSR = base + reg >> 4)
+const X2APIC_MSR_BASE: u32 = 0x800;
 
 const APIC_BASE: u32 = 0xFEE00000;
 
@@ -27,19 +33,42 @@ const APICREG_EOI: u32 = 0xB0;
 // 16   -> mask bit (1 = mask the IRQ, NMI still fires)
 const APICREG_LINT0: u32 = 0x350;
 const APICREG_LINT1: u32 = 0x360;
-// 0:7 -> interrupt vector to be fired
-// 12  -> interrupt status (0 = idle, 1 = interrupt pending)
-// 16  -> mask bit (1 = mask the IRQ)
+// 0:7   -> interrupt vector to be fired
+// 12    -> interrupt status (0 = idle, 1 = interrupt pending)
+// 16    -> mask bit (1 = mask the IRQ)
+// 17:18 -> timer mode (00 one-shot, 01 periodic, 10 TSC-deadline)
 const APICREG_TIMER: u32 = 0x320;
+const APICREG_TIMER_INITIAL_COUNT: u32 = 0x380;
+const APICREG_TIMER_CURRENT_COUNT: u32 = 0x390;
+// 0:1, 3 -> divide value (0b011 = divide by 16)
+const APICREG_TIMER_DIVIDE: u32 = 0x3E0;
+
+const TIMER_MASKED: u32 = 1 << 16;
+const TIMER_DIVIDE_BY_16: u32 = 0b011;
+// How long the timer is measured against the PIT
+const TIMER_CALIBRATION_US: u64 = 10_000;
+
+// Vector fired when an interrupt is withdrawn while being delivered (must not be acknowledged)
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TimerMode {
+    OneShot = 0b00,
+    Periodic = 0b01,
+    TscDeadline = 0b10,
+}
 
 pub static mut LOCAL_APIC: LocalApic = LocalApic {
     base_address: VirtAddr::zero(),
     is_ver2: false,
+    timer_ticks_per_ms: 0,
 };
 
 pub struct LocalApic {
     pub base_address: VirtAddr,
     pub is_ver2: bool,
+    // Frequency of the timer (divided by 16), every core has the same one
+    pub timer_ticks_per_ms: u32,
 }
 
 #[derive(Debug)]
@@ -47,6 +76,11 @@ pub enum InitError {
     ApicNotSupported,
 }
 
+#[derive(Debug)]
+pub enum TimerError {
+    TscDeadlineNotSupported,
+}
+
 impl LocalApic {
     pub unsafe fn init(&mut self, base_address: VirtAddr) -> Result<(), InitError> {
         let cpuid = CpuId::new();
@@ -62,18 +96,112 @@ impl LocalApic {
         self.init_ap();
         println!("APIC ID: {}", self.id());
         println!("APIC VERSION: {}", self.version());
+        self.calibrate_timer();
+        println!("APIC TIMER: {} ticks/ms, TSC: {} ticks/ms", self.timer_ticks_per_ms, tsc::ticks_per_ms());
         Ok(())
     }
 
-    unsafe fn init_ap(&mut self) {
+    /// Enables the local APIC of the current core
+    pub unsafe fn init_ap(&mut self) {
         if self.is_ver2 {
             // Enable the Local APIC
-            MSR_IA32_APIC_BASE.write(MSR_IA32_APIC_BASE.read() | 1 << 10);
+            let mut apic_base = MSR_IA32_APIC_BASE;
+            apic_base.write(apic_base.read() | 1 << 10);
             // Set the Spurious Interrupt Vector Register bit 8 to start receiving interrupts
-            MSR_IA32_X2APIC_SIVR.write(0x100);
+            let mut sivr = MSR_IA32_X2APIC_SIVR;
+            sivr.write(0x100 | SPURIOUS_VECTOR as u64);
         } else {
             // Set the Spurious Interrupt Vector Register bit 8 to start receiving interrupts
-            self.write(APICREG_SUPRIOUS, 0x100);
+            self.write(APICREG_SUPRIOUS, 0x100 | SPURIOUS_VECTOR as u32);
+        }
+    }
+
+    /// Measures the timer (and the TSC, if cpuid doesn't tell us) frequency against the PIT
+    unsafe fn calibrate_timer(&mut self) {
+        self.write_reg(APICREG_TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+        self.write_reg(APICREG_TIMER, TIMER_MASKED | (TimerMode::OneShot as u32) << 17);
+
+        let tsc_start = tsc::rdtsc();
+        self.write_reg(APICREG_TIMER_INITIAL_COUNT, u32::MAX);
+        pit::wait_us(TIMER_CALIBRATION_US);
+        let remaining = self.read_reg(APICREG_TIMER_CURRENT_COUNT);
+        let tsc_end = tsc::rdtsc();
+        // Stop the timer
+        self.write_reg(APICREG_TIMER_INITIAL_COUNT, 0);
+
+        let calibration_ms = TIMER_CALIBRATION_US / 1000;
+        self.timer_ticks_per_ms = ((u32::MAX - remaining) as u64 / calibration_ms) as u32;
+        let measured = (tsc_end - tsc_start) / calibration_ms;
+        tsc::set_ticks_per_ms(tsc::cpuid_ticks_per_ms().unwrap_or(measured));
+        if !tsc::is_invariant() {
+            println!("WARNING: the TSC is not invariant, the monotonic clock might drift");
+        }
+    }
+
+    fn timer_count(&self, us: u64) -> u32 {
+        (self.timer_ticks_per_ms as u64 * us / 1000).clamp(1, u32::MAX as u64) as u32
+    }
+
+    unsafe fn setup_timer(&mut self, mode: TimerMode, vector: u8) {
+        self.write_reg(APICREG_TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+        self.write_reg(APICREG_TIMER, (mode as u32) << 17 | vector as u32);
+    }
+
+    /// Fires the interrupt every period_us microseconds on the current core
+    pub unsafe fn set_timer_periodic(&mut self, vector: u8, period_us: u64) {
+        self.setup_timer(TimerMode::Periodic, vector);
+        self.write_reg(APICREG_TIMER_INITIAL_COUNT, self.timer_count(period_us));
+    }
+
+    /// Fires the interrupt once after us microseconds on the current core
+    pub unsafe fn set_timer_one_shot(&mut self, vector: u8, us: u64) {
+        self.setup_timer(TimerMode::OneShot, vector);
+        self.write_reg(APICREG_TIMER_INITIAL_COUNT, self.timer_count(us));
+    }
+
+    /// Fires the interrupt once when the TSC reaches the deadline on the current core
+    pub unsafe fn set_timer_tsc_deadline(&mut self, vector: u8, deadline: u64) -> Result<(), TimerError> {
+        let features = CpuId::new().get_feature_info().unwrap();
+        if !features.has_tsc_deadline() {
+            return Err(TimerError::TscDeadlineNotSupported);
+        }
+        self.setup_timer(TimerMode::TscDeadline, vector);
+        // The LVT write must be serialized before arming the deadline
+        core::arch::x86_64::_mm_mfence();
+        let mut tsc_deadline = MSR_IA32_TSC_DEADLINE;
+        tsc_deadline.write(deadline);
+        Ok(())
+    }
+
+    pub unsafe fn stop_timer(&mut self) {
+        let was_deadline = (self.read_reg(APICREG_TIMER) >> 17) & 0b11 == TimerMode::TscDeadline as u32;
+        if was_deadline {
+            let mut tsc_deadline = MSR_IA32_TSC_DEADLINE;
+            tsc_deadline.write(0);
+        }
+        self.write_reg(APICREG_TIMER, TIMER_MASKED);
+        self.write_reg(APICREG_TIMER_INITIAL_COUNT, 0);
+    }
+
+    /// Signals the end of the interrupt being handled
+    pub fn eoi(&mut self) {
+        unsafe { self.write_reg(APICREG_EOI, 0) }
+    }
+
+    // Register access that works both in xAPIC and x2APIC mode
+    unsafe fn read_reg(&self, reg: u32) -> u32 {
+        if self.is_ver2 {
+            Msr::new(X2APIC_MSR_BASE + (reg >> 4)).read() as u32
+        } else {
+            self.read(reg)
+        }
+    }
+
+    unsafe fn write_reg(&self, reg: u32, val: u32) {
+        if self.is_ver2 {
+            Msr::new(X2APIC_MSR_BASE + (reg >> 4)).write(val as u64)
+        } else {
+            self.write(reg, val)
         }
     }
 
@@ -93,7 +221,8 @@ impl LocalApic {
         if self.is_ver2 {
             unsafe { MSR_IA32_X2APIC_APICID.read() as u32 }
         } else {
-            unsafe { self.read(0x20) }
+            // xAPIC keeps the id in the top byte
+            unsafe { self.read(0x20) >> 24 }
         }
     }
 
@@ -114,9 +243,22 @@ impl LocalApic {
         }
     }
 
+    /// Sends a fixed interrupt with vector to the core with the given local APIC id
+    pub fn send_ipi(&mut self, apic_id: u32, vector: u8) {
+        // Fixed delivery, physical destination, assert
+        let mut icr = 0x4000 | vector as u64;
+        if self.is_ver2 {
+            icr |= (apic_id as u64) << 32;
+        } else {
+            icr |= (apic_id as u64) << 56;
+        }
+        self.set_icr(icr);
+    }
+
     pub fn set_icr(&mut self, value: u64) {
         if self.is_ver2 {
-            unsafe { MSR_IA32_X2APIC_ICR.write(value) }
+            let mut icr = MSR_IA32_X2APIC_ICR;
+            unsafe { icr.write(value) }
         } else {
             const DELIVERY_MASK: u32 = 1 << 12;
             unsafe {
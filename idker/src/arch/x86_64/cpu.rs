@@ -0,0 +1,62 @@
+use core::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+
+use super::apic::LOCAL_APIC;
+
+// Logical ids of the cores that run tasks (0 is the bootstrap processor), they are contiguous
+// unlike the local APIC ids, so they can be used as indexes and as bits of a CpuMask.
+
+pub const MAX_CPUS: usize = 64;
+
+// Bit i set = logical cpu i
+pub type CpuMask = u64;
+
+static CPU_COUNT: AtomicUsize = AtomicUsize::new(0);
+// Cores started at boot, the application processors register themselves only later
+static STARTED_CPUS: AtomicUsize = AtomicUsize::new(1);
+
+#[thread_local]
+static CPU_ID: AtomicUsize = AtomicUsize::new(0);
+
+const NO_APIC_ID: AtomicU32 = AtomicU32::new(0);
+// Local APIC id of every logical cpu, to send them interrupts
+static APIC_IDS: [AtomicU32; MAX_CPUS] = [NO_APIC_ID; MAX_CPUS];
+
+/// Assigns the next logical id to the current core, must be called once per core
+/// (after its local APIC is initialized)
+pub fn register_cpu() -> usize {
+    let id = CPU_COUNT.fetch_add(1, Ordering::SeqCst);
+    assert!(id < MAX_CPUS, "Too many cpus");
+    CPU_ID.store(id, Ordering::SeqCst);
+    APIC_IDS[id].store(unsafe { LOCAL_APIC.id() }, Ordering::SeqCst);
+    id
+}
+
+pub fn cpu_id() -> usize {
+    CPU_ID.load(Ordering::SeqCst)
+}
+
+pub fn apic_id(cpu: usize) -> u32 {
+    APIC_IDS[cpu].load(Ordering::SeqCst)
+}
+
+pub fn cpu_count() -> usize {
+    CPU_COUNT.load(Ordering::SeqCst)
+}
+
+/// Called by the bootstrap processor when an application processor has started
+pub fn cpu_started() {
+    STARTED_CPUS.fetch_add(1, Ordering::SeqCst);
+}
+
+/// Cores that will run tasks (registered or not yet)
+pub fn started_cpu_count() -> usize {
+    STARTED_CPUS.load(Ordering::SeqCst)
+}
+
+/// Mask of the registered cores
+pub fn online_mask() -> CpuMask {
+    match cpu_count() {
+        MAX_CPUS => CpuMask::MAX,
+        x => (1 << x) - 1,
+    }
+}
@@ -0,0 +1,106 @@
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use alloc::{vec, vec::Vec};
+use raw_cpuid::CpuId;
+use x86_64::registers::{control::{Cr0, Cr0Flags, Cr4, Cr4Flags}, xcontrol::{XCr0, XCr0Flags}};
+
+// The kernel is built without SSE, so the x87/SSE/AVX registers only ever hold user state and they
+// are saved (eagerly) only when switching task.
+// With XSAVE every user component that the cpu supports is enabled in XCR0 and the save area is
+// sized from cpuid, otherwise FXSAVE is used (x87 and SSE only).
+
+const FXSAVE_AREA_SIZE: usize = 512;
+// Offsets in the legacy region (shared by FXSAVE and XSAVE)
+const FCW_OFFSET: usize = 0;
+const MXCSR_OFFSET: usize = 24;
+// Default control words: every exception is masked
+const FCW_DEFAULT: u16 = 0x037F;
+const MXCSR_DEFAULT: u32 = 0x1F80;
+
+static USE_XSAVE: AtomicBool = AtomicBool::new(false);
+static AREA_SIZE: AtomicUsize = AtomicUsize::new(FXSAVE_AREA_SIZE);
+
+/// Enables the FPU, SSE and AVX for userspace on the current cpu, every cpu must call it
+pub fn init() {
+    let cpuid = CpuId::new();
+    let features = cpuid.get_feature_info().expect("Cannot read cpu features");
+    assert!(features.has_fxsave_fxstor(), "FXSAVE not supported");
+
+    unsafe {
+        Cr0::update(|x| {
+            x.remove(Cr0Flags::EMULATE_COPROCESSOR | Cr0Flags::TASK_SWITCHED);
+            x.insert(Cr0Flags::MONITOR_COPROCESSOR | Cr0Flags::NUMERIC_ERROR);
+        });
+        Cr4::update(|x| x.insert(Cr4Flags::OSFXSR | Cr4Flags::OSXMMEXCPT_ENABLE));
+    }
+
+    let state_info = cpuid.get_extended_state_info();
+    let size = match state_info {
+        Some(info) if features.has_xsave() => {
+            let mut xcr0 = XCr0Flags::X87 | XCr0Flags::SSE;
+            if features.has_avx() && info.xcr0_supports_avx_256() {
+                xcr0 |= XCr0Flags::AVX;
+                if info.xcr0_supports_avx512_opmask() && info.xcr0_supports_avx512_zmm_hi256()
+                        && info.xcr0_supports_avx512_zmm_hi16() {
+                    xcr0 |= XCr0Flags::OPMASK | XCr0Flags::ZMM_HI256 | XCr0Flags::HI16_ZMM;
+                }
+            }
+            unsafe {
+                Cr4::update(|x| x.insert(Cr4Flags::OSXSAVE));
+                XCr0::write(xcr0);
+            }
+            USE_XSAVE.store(true, Ordering::SeqCst);
+            // The size depends on the enabled components, read it again
+            CpuId::new().get_extended_state_info().unwrap().xsave_area_size_enabled_features() as usize
+        },
+        _ => FXSAVE_AREA_SIZE,
+    };
+    AREA_SIZE.fetch_max(size, Ordering::SeqCst);
+
+    unsafe { core::arch::asm!("fninit", options(nomem, nostack)) };
+}
+
+// XSAVE wants the area aligned to 64 bytes (FXSAVE to 16)
+#[derive(Clone, Copy)]
+#[repr(C, align(64))]
+struct AreaChunk([u8; 64]);
+
+/// The saved x87/SSE/AVX registers of a task
+pub struct FpuState {
+    area: Vec<AreaChunk>,
+}
+
+impl FpuState {
+    /// Initial state: registers cleared and exceptions masked
+    pub fn new() -> Self {
+        let chunks = (AREA_SIZE.load(Ordering::SeqCst) + 63) / 64;
+        let mut area = vec![AreaChunk([0; 64]); chunks];
+        // A zeroed XSAVE header means "initial state" for every component, but the control words
+        // are always read from the legacy region
+        let legacy = &mut area[0].0;
+        legacy[FCW_OFFSET..][..2].copy_from_slice(&FCW_DEFAULT.to_le_bytes());
+        legacy[MXCSR_OFFSET..][..4].copy_from_slice(&MXCSR_DEFAULT.to_le_bytes());
+        FpuState { area }
+    }
+
+    /// Saves the registers of the current cpu
+    pub unsafe fn save(&mut self) {
+        let ptr = self.area.as_mut_ptr();
+        if USE_XSAVE.load(Ordering::SeqCst) {
+            // Every component enabled in XCR0
+            core::arch::asm!("xsave64 [{}]", in(reg) ptr, in("eax") u32::MAX, in("edx") u32::MAX, options(nostack));
+        } else {
+            core::arch::asm!("fxsave64 [{}]", in(reg) ptr, options(nostack));
+        }
+    }
+
+    /// Loads the saved registers in the current cpu
+    pub unsafe fn restore(&self) {
+        let ptr = self.area.as_ptr();
+        if USE_XSAVE.load(Ordering::SeqCst) {
+            core::arch::asm!("xrstor64 [{}]", in(reg) ptr, in("eax") u32::MAX, in("edx") u32::MAX, options(nostack, readonly));
+        } else {
+            core::arch::asm!("fxrstor64 [{}]", in(reg) ptr, options(nostack, readonly));
+        }
+    }
+}
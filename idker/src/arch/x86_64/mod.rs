@@ -1,7 +1,14 @@
 pub mod acpi;
 pub mod apic;
 pub mod consts;
+pub mod cpu;
+pub mod fpu;
 #[cfg(feature = "multi_core")]
 pub mod multi_core;
 pub mod paging;
+pub mod pit;
+pub mod random;
+pub mod rtc;
 pub mod start;
+pub mod tlb;
+pub mod tsc;
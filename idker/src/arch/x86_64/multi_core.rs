@@ -4,7 +4,7 @@ use crate::{
         apic::LOCAL_APIC,
         paging::{get_page_table, physical_memory_offset},
     },
-    hlt_loop, print, println,
+    print, println,
 };
 use acpi::platform::{Processor, ProcessorInfo, ProcessorState};
 use alloc::boxed::Box;
@@ -85,6 +85,7 @@ pub fn init_ap_processor(p: &Processor) {
     while !AP_READY.load(Ordering::SeqCst) {
         unsafe { core::arch::x86_64::_mm_pause() };
     }
+    super::cpu::cpu_started();
     println!("AP {} READY!", p.processor_uid);
 }
 
@@ -109,6 +110,11 @@ pub fn init<A: Allocator>(proc_info: &ProcessorInfo<A>) {
     }
 }
 
+/// Lets the application processors start scheduling (the task registry must be ready)
+pub fn release_aps() {
+    BSP_READY.store(true, Ordering::SeqCst);
+}
+
 struct Trampoline;
 
 impl Trampoline {
@@ -172,7 +178,7 @@ pub struct KernelArgsAp {
 }
 
 pub unsafe extern "C" fn kstart_ap(args_ptr: *const KernelArgsAp) -> ! {
-    use crate::{gdt, interrupts};
+    use crate::{context::{TaskContext, become_idle_task, idle_loop}, gdt, interrupts, syscalls};
 
     let args = &*args_ptr;
 
@@ -183,6 +189,9 @@ pub unsafe extern "C" fn kstart_ap(args_ptr: *const KernelArgsAp) -> ! {
         crate::arch::x86_64::paging::setup_thread_data(args.cpu_id, &mut *frame_allocator);
     }
     gdt::init();
+    crate::arch::fpu::init();
+    LOCAL_APIC.init_ap();
+    interrupts::start_scheduler_tick();
 
     println!("READY: {}", args.cpu_id);
 
@@ -193,5 +202,7 @@ pub unsafe extern "C" fn kstart_ap(args_ptr: *const KernelArgsAp) -> ! {
     }
     println!("START! {}", args.cpu_id);
 
-    hlt_loop();
+    syscalls::setup_syscalls();
+    become_idle_task(TaskContext::create_idle());
+    idle_loop();
 }
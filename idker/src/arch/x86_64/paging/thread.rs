@@ -1,7 +1,7 @@
 use core::ptr;
 
 use x86_64::{
-    registers::model_specific::FsBase,
+    registers::model_specific::{FsBase, GsBase, KernelGsBase},
     structures::paging::{FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB},
     VirtAddr,
 };
@@ -9,6 +9,7 @@ use x86_64::{
 use crate::{
     allocator::HeapFrameAllocator,
     arch::x86_64::consts::{KERNEL_THREAD_DATA_START, KERNEL_THREAD_STORAGE_SIZE},
+    syscalls::KERNEL_FS_BASE,
 };
 
 use super::get_page_table;
@@ -100,7 +101,6 @@ pub unsafe fn setup_thread_data(cpu: u64, frame_allocator: &mut HeapFrameAllocat
 
     *data_offset.as_mut_ptr() = end;
 
-    // TODO: USE GS
     // Load the thread data pointer directly into the GS register
     // when you access a thread local variable the kernel uses GS
     // to know where that data is actually stored
@@ -111,6 +111,10 @@ pub unsafe fn setup_thread_data(cpu: u64, frame_allocator: &mut HeapFrameAllocat
     // we are using always target fs because this is what linux x86-64 does, and I don't want to
     // patch the compilers (Redox way) or write custom-made macros to access thread_local variables
     // (fuchsia, linux, and many other kernels).
-    //GsBase::write(data_offset);
     FsBase::write(data_offset);
+    // Userspace has its own FS base, so GS points to the same data while in the kernel: the
+    // code entering from userspace uses it to find the kernel FS base again
+    GsBase::write(data_offset);
+    KernelGsBase::write(VirtAddr::zero());
+    KERNEL_FS_BASE = data_offset.as_u64();
 }
@@ -0,0 +1,40 @@
+use core::hint::spin_loop;
+
+use x86_64::instructions::port::Port;
+
+// The legacy Programmable Interval Timer, its frequency is fixed so it's only used to measure
+// the frequency of the other timers (local APIC timer and TSC) at boot.
+// Channel 2 is used since it can be polled through the speaker port without interrupts.
+
+const PIT_FREQUENCY: u64 = 1_193_182;
+const PIT_CHANNEL2: u16 = 0x42;
+const PIT_COMMAND: u16 = 0x43;
+// Bit 0: channel 2 gate, bit 1: speaker enable, bit 5: channel 2 output
+const SPEAKER_PORT: u16 = 0x61;
+
+/// Busy waits for the specified microseconds (at most ~54ms, the counter is 16 bits)
+pub fn wait_us(us: u64) {
+    let count = PIT_FREQUENCY * us / 1_000_000;
+    assert!(count > 0 && count <= 0xFFFF, "PIT cannot wait for {}us", us);
+
+    let mut speaker = Port::<u8>::new(SPEAKER_PORT);
+    let mut command = Port::<u8>::new(PIT_COMMAND);
+    let mut channel2 = Port::<u8>::new(PIT_CHANNEL2);
+    unsafe {
+        // Stop the channel and keep the speaker quiet
+        let status = speaker.read() & !0x03;
+        speaker.write(status);
+        // Channel 2, lobyte/hibyte access, mode 0 (interrupt on terminal count), binary
+        command.write(0b1011_0000);
+        channel2.write(count as u8);
+        channel2.write((count >> 8) as u8);
+        // Open the gate, the count starts now
+        speaker.write(status | 0x01);
+
+        // The output goes high when the count reaches 0
+        while speaker.read() & 0x20 == 0 {
+            spin_loop();
+        }
+        speaker.write(status);
+    }
+}
@@ -0,0 +1,103 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use lazy_static::lazy_static;
+use raw_cpuid::CpuId;
+use spin::Mutex;
+
+use super::tsc;
+
+// Random numbers from the RDRAND instruction. Cpus without it get a mix of the TSC and a counter,
+// good enough to make addresses hard to guess but not to be used as a secret.
+// Values that are needed often (ex. the address space layout of every process) come from a
+// xoshiro256** generator seeded with RDRAND instead, it's much faster.
+
+// RDRAND can fail when the entropy source is exhausted, Intel suggests retrying 10 times
+const RDRAND_RETRIES: usize = 10;
+
+lazy_static! {
+    static ref HAS_RDRAND: bool = CpuId::new().get_feature_info().map_or(false, |x| x.has_rdrand());
+    static ref GENERATOR: Mutex<Xoshiro256> = Mutex::new(Xoshiro256::seeded());
+}
+static FALLBACK_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+fn rdrand() -> Option<u64> {
+    for _ in 0..RDRAND_RETRIES {
+        let value: u64;
+        let ok: u8;
+        unsafe {
+            core::arch::asm!(
+                "rdrand {}",
+                "setc {}",
+                out(reg) value,
+                out(reg_byte) ok,
+                options(nomem, nostack),
+            );
+        }
+        if ok != 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+/// SplitMix64 finalizer, spreads the few changing bits of the input over the whole output
+fn mix(mut x: u64) -> u64 {
+    x = (x ^ (x >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    x ^ (x >> 31)
+}
+
+pub fn random_u64() -> u64 {
+    if *HAS_RDRAND {
+        if let Some(x) = rdrand() {
+            return x;
+        }
+    }
+    let count = FALLBACK_COUNTER.fetch_add(0x9E37_79B9_7F4A_7C15, Ordering::Relaxed);
+    mix(tsc::rdtsc() ^ count)
+}
+
+pub fn fill_bytes(buf: &mut [u8]) {
+    for chunk in buf.chunks_mut(8) {
+        let x = random_u64().to_ne_bytes();
+        chunk.copy_from_slice(&x[..chunk.len()]);
+    }
+}
+
+struct Xoshiro256 {
+    state: [u64; 4],
+}
+
+impl Xoshiro256 {
+    fn seeded() -> Self {
+        let mut state = [0; 4];
+        for x in state.iter_mut() {
+            *x = random_u64();
+        }
+        // An all-zero state would only generate zeros
+        if state == [0; 4] {
+            state[0] = 1;
+        }
+        Xoshiro256 { state }
+    }
+
+    fn next(&mut self) -> u64 {
+        let s = &mut self.state;
+        let result = s[1].wrapping_mul(5).rotate_left(7).wrapping_mul(9);
+        let t = s[1] << 17;
+        s[2] ^= s[0];
+        s[3] ^= s[1];
+        s[1] ^= s[2];
+        s[0] ^= s[3];
+        s[2] ^= t;
+        s[3] = s[3].rotate_left(45);
+        result
+    }
+}
+
+/// Number in [0, bound) from the seeded generator
+pub fn below(bound: u64) -> u64 {
+    let x = GENERATOR.lock().next();
+    // Multiply and keep the high part, it's less biased than the modulo
+    ((x as u128 * bound as u128) >> 64) as u64
+}
@@ -0,0 +1,110 @@
+use x86_64::instructions::{interrupts, port::Port};
+
+// The CMOS real time clock, it only has a resolution of one second so it's read once at boot
+// to know the wall-clock time, then the TSC is used to keep track of it.
+
+const CMOS_ADDRESS: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+// Bit 7 of the address port disables NMIs, keep them enabled
+const CMOS_NMI_DISABLE: u8 = 0x80;
+
+const RTC_SECONDS: u8 = 0x00;
+const RTC_MINUTES: u8 = 0x02;
+const RTC_HOURS: u8 = 0x04;
+const RTC_DAY: u8 = 0x07;
+const RTC_MONTH: u8 = 0x08;
+const RTC_YEAR: u8 = 0x09;
+const RTC_STATUS_A: u8 = 0x0A;
+const RTC_STATUS_B: u8 = 0x0B;
+
+const STATUS_A_UPDATING: u8 = 0x80;
+const STATUS_B_24_HOURS: u8 = 0x02;
+const STATUS_B_BINARY: u8 = 0x04;
+const HOURS_PM: u8 = 0x80;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct RtcTime {
+    seconds: u8,
+    minutes: u8,
+    hours: u8,
+    day: u8,
+    month: u8,
+    year: u8,
+}
+
+unsafe fn read_cmos(reg: u8) -> u8 {
+    Port::<u8>::new(CMOS_ADDRESS).write(reg & !CMOS_NMI_DISABLE);
+    Port::<u8>::new(CMOS_DATA).read()
+}
+
+unsafe fn read_raw() -> RtcTime {
+    // Wait for the end of an update, or we might read a half-updated date
+    while read_cmos(RTC_STATUS_A) & STATUS_A_UPDATING != 0 {
+        core::hint::spin_loop();
+    }
+    RtcTime {
+        seconds: read_cmos(RTC_SECONDS),
+        minutes: read_cmos(RTC_MINUTES),
+        hours: read_cmos(RTC_HOURS),
+        day: read_cmos(RTC_DAY),
+        month: read_cmos(RTC_MONTH),
+        year: read_cmos(RTC_YEAR),
+    }
+}
+
+fn from_bcd(x: u8) -> u8 {
+    (x & 0x0F) + (x >> 4) * 10
+}
+
+/// Days from 1970-01-01 to the given date (proleptic gregorian calendar)
+fn days_from_civil(year: i64, month: u64, day: u64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = (year - era * 400) as u64;
+    let month_index = (month + 9) % 12;// March = 0
+    let day_of_year = (153 * month_index + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era as i64 - 719468
+}
+
+/// Reads the wall-clock time as seconds since the unix epoch
+pub fn read_unix_time() -> u64 {
+    let (time, status_b) = interrupts::without_interrupts(|| unsafe {
+        // Read until two consecutive reads match, the clock might tick in between
+        let mut time = read_raw();
+        loop {
+            let next = read_raw();
+            if next == time {
+                break;
+            }
+            time = next;
+        }
+        (time, read_cmos(RTC_STATUS_B))
+    });
+
+    let is_pm = time.hours & HOURS_PM != 0;
+    let mut time = RtcTime { hours: time.hours & !HOURS_PM, ..time };
+    if status_b & STATUS_B_BINARY == 0 {
+        time = RtcTime {
+            seconds: from_bcd(time.seconds),
+            minutes: from_bcd(time.minutes),
+            hours: from_bcd(time.hours),
+            day: from_bcd(time.day),
+            month: from_bcd(time.month),
+            year: from_bcd(time.year),
+        };
+    }
+    if status_b & STATUS_B_24_HOURS == 0 {
+        // 12 hours mode, 12 AM is midnight
+        time.hours %= 12;
+        if is_pm {
+            time.hours += 12;
+        }
+    }
+
+    // The century register isn't always there, assume we're in the 21st century
+    let year = 2000 + time.year as i64;
+    let days = days_from_civil(year, time.month as u64, time.day as u64);
+    let seconds = days * 86400 + time.hours as i64 * 3600 + time.minutes as i64 * 60 + time.seconds as i64;
+    seconds.max(0) as u64
+}
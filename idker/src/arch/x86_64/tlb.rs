@@ -0,0 +1,98 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use spin::Mutex;
+use x86_64::{VirtAddr, instructions::tlb, structures::paging::{PageSize, Size4KiB}};
+
+use crate::println;
+
+use super::{apic::LOCAL_APIC, cpu::{self, MAX_CPUS}, tsc};
+
+// A cpu only caches the translations of the page table it has loaded (user pages aren't global
+// and PCIDs aren't used, loading CR3 flushes them), so when memory is unmapped only the cpus
+// running the same page table have to flush. They are asked with an IPI and the cpu that
+// unmapped waits for all of them before the frames can be reused.
+// Every shootdown has its own generation, a cpu acknowledges the generation it has seen, so a late
+// answer to a shootdown that timed out is never taken as an answer to the next one.
+
+pub const TLB_SHOOTDOWN_VECTOR: u8 = 0xF0;
+// Flushing the pages one by one is slower than flushing everything after this many
+const MAX_INVLPG_PAGES: u64 = 32;
+// A cpu that doesn't answer by then is stuck (or the IPI went somewhere else), the caller gives up
+const SHOOTDOWN_TIMEOUT_MS: u64 = 1000;
+
+const NO_TABLE: AtomicU64 = AtomicU64::new(0);
+// Physical address of the page table loaded by every cpu
+static ACTIVE_TABLES: [AtomicU64; MAX_CPUS] = [NO_TABLE; MAX_CPUS];
+
+// Only one shootdown at a time, the range to flush is in FLUSH_START and FLUSH_PAGES
+// (stored before GENERATION, so a cpu that sees a generation sees its range too)
+static SHOOTDOWN: Mutex<()> = Mutex::new(());
+static FLUSH_START: AtomicU64 = AtomicU64::new(0);
+static FLUSH_PAGES: AtomicU64 = AtomicU64::new(0);
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+const NONE_ACKNOWLEDGED: AtomicU64 = AtomicU64::new(0);
+// Last generation flushed by every cpu
+static ACKNOWLEDGED: [AtomicU64; MAX_CPUS] = [NONE_ACKNOWLEDGED; MAX_CPUS];
+
+/// Must be called before the current cpu loads the page table at table (physical address)
+pub fn set_active_table(table: u64) {
+    ACTIVE_TABLES[cpu::cpu_id()].store(table, Ordering::SeqCst);
+}
+
+fn flush_local(start: VirtAddr, pages: u64) {
+    if pages > MAX_INVLPG_PAGES {
+        tlb::flush_all();
+    } else {
+        for i in 0..pages {
+            tlb::flush(start + i * Size4KiB::SIZE);
+        }
+    }
+}
+
+/// Flushes pages pages from start on every cpu running the page table at table (physical address),
+/// returns false if some of them didn't answer in time (they might still use the old entries).
+/// The entries must already be changed, interrupts must be enabled and no lock that the other cpus
+/// might wait for with interrupts disabled can be held (ex. the page table or a task)
+pub fn shootdown(table: u64, start: VirtAddr, pages: u64) -> bool {
+    let current = cpu::cpu_id();
+    if ACTIVE_TABLES[current].load(Ordering::SeqCst) == table {
+        flush_local(start, pages);
+    }
+
+    let _guard = SHOOTDOWN.lock();
+    // A cpu that loads the table after this will only see the new entries
+    let targets = (0..cpu::cpu_count())
+        .filter(|x| *x != current && ACTIVE_TABLES[*x].load(Ordering::SeqCst) == table)
+        .fold(0u64, |mask, x| mask | 1 << x);
+    if targets == 0 {
+        return true;
+    }
+
+    FLUSH_START.store(start.as_u64(), Ordering::SeqCst);
+    FLUSH_PAGES.store(pages, Ordering::SeqCst);
+    let generation = GENERATION.fetch_add(1, Ordering::SeqCst) + 1;
+    for x in (0..cpu::cpu_count()).filter(|x| targets & (1 << x) != 0) {
+        unsafe { LOCAL_APIC.send_ipi(cpu::apic_id(x), TLB_SHOOTDOWN_VECTOR) };
+    }
+    let deadline = tsc::rdtsc() + SHOOTDOWN_TIMEOUT_MS * tsc::ticks_per_ms();
+    let mut pending = targets;
+    while pending != 0 {
+        pending = (0..cpu::cpu_count())
+            .filter(|x| pending & (1 << x) != 0 && ACKNOWLEDGED[*x].load(Ordering::SeqCst) < generation)
+            .fold(0u64, |mask, x| mask | 1 << x);
+        if pending != 0 && tsc::rdtsc() > deadline {
+            println!("TLB shootdown not acknowledged by cpus {:#x}", pending);
+            return false;
+        }
+        core::hint::spin_loop();
+    }
+    true
+}
+
+/// Handles the shootdown IPI
+pub fn on_shootdown() {
+    let generation = GENERATION.load(Ordering::SeqCst);
+    let start = VirtAddr::new(FLUSH_START.load(Ordering::SeqCst));
+    flush_local(start, FLUSH_PAGES.load(Ordering::SeqCst));
+    ACKNOWLEDGED[cpu::cpu_id()].fetch_max(generation, Ordering::SeqCst);
+}
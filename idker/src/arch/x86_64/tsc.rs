@@ -0,0 +1,34 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use raw_cpuid::CpuId;
+
+// Time Stamp Counter, its frequency is read from cpuid when the cpu reports it, otherwise it's
+// measured at boot (see LocalApic::calibrate_timer)
+static TSC_TICKS_PER_MS: AtomicU64 = AtomicU64::new(0);
+
+pub fn rdtsc() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+pub fn set_ticks_per_ms(ticks: u64) {
+    TSC_TICKS_PER_MS.store(ticks, Ordering::SeqCst);
+}
+
+/// TSC ticks in a millisecond (0 if not calibrated yet)
+pub fn ticks_per_ms() -> u64 {
+    TSC_TICKS_PER_MS.load(Ordering::SeqCst)
+}
+
+/// TSC frequency enumerated by the cpu (leaf 0x15), it's exact while measuring it is not
+pub fn cpuid_ticks_per_ms() -> Option<u64> {
+    CpuId::new().get_tsc_info()
+        .and_then(|x| x.tsc_frequency())
+        .map(|hz| hz / 1000)
+        .filter(|x| *x > 0)
+}
+
+/// An invariant TSC ticks at a constant rate regardless of power states, so it can be used as a clock
+pub fn is_invariant() -> bool {
+    CpuId::new().get_advanced_power_mgmt_info()
+        .map_or(false, |x| x.has_invariant_tsc())
+}
@@ -1,6 +1,9 @@
+use alloc::sync::Arc;
 use bitflags::bitflags;
 use x86_64::PhysAddr;
 
+use crate::{conversation::ConversationEndpoint, time::Timer};
+
 pub mod syscall;
 
 bitflags! {
@@ -29,6 +32,12 @@ pub enum CapabilityType {
     ProcessSpawn,
     // Allows creation of new channels
     ChannelCreate,
+    // An endpoint of a conversation, allows to talk and listen to it
+    Conversation(ConversationEndpoint),
+    // A one-shot timer, allows to arm, cancel and wait on it
+    Timer(Arc<Timer>),
+    // Allows to make processes more important (ex. real-time)
+    SchedulerPriority,
 }
 
 impl CapabilityType {
@@ -38,6 +47,9 @@ impl CapabilityType {
             CapabilityType::MapVirtualToRam => 2,
             CapabilityType::ProcessSpawn => 3,
             CapabilityType::ChannelCreate => 4,
+            CapabilityType::Conversation(_) => 5,
+            CapabilityType::Timer(_) => 6,
+            CapabilityType::SchedulerPriority => 7,
         }
     }
 }
@@ -3,7 +3,7 @@ use core::num::NonZeroU64;
 use alloc::vec::Vec;
 use x86_64::PhysAddr;
 
-use crate::{context::{TaskId, current_task, current_task_id, tasks}};
+use crate::{context::{TaskId, current_process_id, current_task, tasks}};
 use syscall::{SyscallError, SyscallResult};
 
 use super::{Capability, CapabilityPerms, CapabilityType};
@@ -50,8 +50,8 @@ impl TaskCapabilityStorage {
 
 pub fn clone(cap_handle: CapabilityHandle) -> SyscallResult<CapabilityHandle> {
     let task_lock = current_task();
-    let mut task = task_lock.write();
-    let caps = &mut task.capabilities;
+    let task = task_lock.read();
+    let mut caps = task.capabilities.lock();
     let cap = caps.get(cap_handle)
             .ok_or(SyscallError::WrongCapability)?;
 
@@ -66,15 +66,16 @@ pub fn clone(cap_handle: CapabilityHandle) -> SyscallResult<CapabilityHandle> {
 pub fn inspect(what: usize, index: usize) -> SyscallResult<(usize, usize)> {
     let task_lock = current_task();
     let task = task_lock.read();
+    let caps = task.capabilities.lock();
     match what {
-        0 => Ok((task.capabilities.handles.len(), 0)),
+        0 => Ok((caps.handles.len(), 0)),
         1 => {
-            task.capabilities.handles.get(index)
+            caps.handles.get(index)
                     .map(|x| (x.0, x.1.ctype.cap_id()))
                     .ok_or(SyscallError::WrongParameters)
         }
         2 => {
-            task.capabilities.get(index)
+            caps.get(index)
                     .map(|x| (x.perms.bits() as usize, x.ctype.cap_id()))
                     .ok_or(SyscallError::WrongCapability)
         }
@@ -84,8 +85,9 @@ pub fn inspect(what: usize, index: usize) -> SyscallResult<(usize, usize)> {
 
 pub fn restrict(handle: CapabilityHandle, a: usize, b: usize) -> SyscallResult<()> {
     let task_lock = current_task();
-    let mut task = task_lock.write();
-    let cap = task.capabilities.get_mut(handle).ok_or(SyscallError::WrongCapability)?;
+    let task = task_lock.read();
+    let mut caps = task.capabilities.lock();
+    let cap = caps.get_mut(handle).ok_or(SyscallError::WrongCapability)?;
     match &mut cap.ctype {
         CapabilityType::MapPhysical(from, to) => {
             if a > b { return Err(SyscallError::WrongParameters) }
@@ -105,27 +107,31 @@ pub fn restrict(handle: CapabilityHandle, a: usize, b: usize) -> SyscallResult<(
 
 pub fn cdrop(handle: CapabilityHandle) -> SyscallResult<()> {
     let task_lock = current_task();
-    let mut task = task_lock.write();
-    task.capabilities.delete(handle)
-            .map(|_| ())// Drop capability
+    let task = task_lock.read();
+    let cap = task.capabilities.lock().delete(handle)?;
+    // Dropped without locks, it might close a conversation
+    drop(task);
+    drop(cap);
+    Ok(())
 }
 
 pub fn process_share_transfer(proc: usize, cap_handle: CapabilityHandle, transfer: bool) -> SyscallResult<()> {
     let proc = TaskId(NonZeroU64::new(proc as u64).ok_or(SyscallError::WrongParameters)?);
 
-    if proc == current_task_id() {
+    if proc == current_process_id() {
         return Err(SyscallError::WrongProcess);
     }
 
     let cap = if transfer {
         let current_lock = current_task();
-        let mut current = current_lock.write();
-
-        current.capabilities.delete(cap_handle)?
+        let current = current_lock.read();
+        let removed = current.capabilities.lock().delete(cap_handle)?;
+        removed
     } else {
         let current_lock = current_task();
         let current = current_lock.read();
-        let cap = current.capabilities.get(cap_handle)
+        let caps = current.capabilities.lock();
+        let cap = caps.get(cap_handle)
                 .ok_or(SyscallError::WrongCapability)?;
 
         if !cap.perms.contains(CapabilityPerms::SHAREABLE) {
@@ -141,13 +147,13 @@ pub fn process_share_transfer(proc: usize, cap_handle: CapabilityHandle, transfe
                 .clone()
     };
 
-    let mut target = target_lock.write();
+    let target = target_lock.read();
 
-    if target.parent != Some(current_task_id()) {
+    if target.parent != Some(current_process_id()) {
         return Err(SyscallError::WrongProcess);
     }
 
-    let _new_handle = target.capabilities.insert(cap)?;
+    let _new_handle = target.capabilities.lock().insert(cap)?;
 
     Ok(())
 }
\ No newline at end of file
@@ -1,35 +1,111 @@
-use core::{cmp::{max, min}, convert::TryInto, intrinsics::copy_nonoverlapping, slice};
+use core::{cmp::{max, min}, convert::TryInto, intrinsics::copy_nonoverlapping, mem::{align_of, size_of}, ptr::read_unaligned, slice};
 
-use goblin::{elf64::{header::{ELFMAG, SELFMAG, Header, SIZEOF_EHDR}, program_header::{PF_R, PF_W, PF_X, PT_LOAD, ProgramHeader}}};
+use alloc::vec::Vec;
+use goblin::{elf64::{dynamic::{DT_NEEDED, DT_NULL, DT_REL, DT_RELA, DT_RELAENT, DT_RELASZ, Dyn}, header::{EI_CLASS, EI_DATA, ELFCLASS64, ELFDATA2LSB, ELFMAG, EM_X86_64, ET_DYN, ET_EXEC, SELFMAG, Header, SIZEOF_EHDR}, program_header::{PF_R, PF_W, PF_X, PT_DYNAMIC, PT_INTERP, PT_LOAD, PT_PHDR, PT_TLS, ProgramHeader}, reloc::{R_X86_64_NONE, R_X86_64_RELATIVE, Rela, SIZEOF_RELA, r_type}}};
+use syscall::{SyscallError, SyscallResult};
 
-use x86_64::{VirtAddr, structures::paging::{OffsetPageTable, FrameAllocator, Mapper, Page, PageSize, PageTableFlags, Size4KiB}};
+use x86_64::{VirtAddr, structures::paging::{OffsetPageTable, FrameAllocator, FrameDeallocator, Mapper, Page, PageSize, PageTableFlags, Size4KiB, Translate, mapper::MapToError}};
 use crate::{allocator::get_frame_allocator, arch::paging::physical_memory_offset, syscalls::check_addr_userspace};
 
+use super::page_table::UserPageTable;
 
+// The TLS block of the initial thread lives here, below the kernel data page
+const USERSPACE_TLS_ADDR: u64 = 0x3F00_0000;
+const TLS_MAX_SIZE: u64 = 0x80_0000;
+// Only the self pointer is needed, but libcs keep more there (ex. the stack guard at fs:0x28)
+const TLS_TCB_SIZE: u64 = 64;
+const TLS_TCB_ALIGN: u64 = 16;
+// Packed relative relocations, goblin doesn't know them yet
+const DT_RELR: u64 = 36;
+
+// Programs come from userspace, nothing in them can be trusted: every header is checked before
+// it's used and a program that fails to load leaves nothing mapped behind.
+// Static executables (ET_EXEC) are loaded where they are linked, static position-independent ones
+// (ET_DYN) are moved by a bias and their R_X86_64_RELATIVE relocations are applied.
+// Programs that ask for a loader (PT_INTERP) are only mapped, the loader does the rest.
 
 pub struct Elf<'a> {
     data: &'a [u8],
+    // Added to every address of the program
+    bias: u64,
 }
 
 impl<'a> Elf<'a> {
-    pub fn new(data: &'a [u8]) -> Result<Elf<'a>, &'static str> {
-        if data.len() <= SIZEOF_EHDR {
-            return Err("Invalid elf size")
+    /// Parses a program, if it's position-independent it will be loaded at pie_base
+    pub fn new(data: &'a [u8], pie_base: u64) -> SyscallResult<Elf<'a>> {
+        if data.len() < SIZEOF_EHDR {
+            return Err(SyscallError::FsNotExecutable);
         }
-        let elf = Elf {
-            data
+        let mut elf = Elf {
+            data,
+            bias: 0,
         };
         let header = elf.header();
         if &header.e_ident[..SELFMAG] != ELFMAG {
-            return Err("Invalid ELF magic, are you sure this is an elf file?")
+            return Err(SyscallError::FsNotExecutable);
+        }
+        // Only x86_64 static executables can run here
+        if header.e_ident[EI_CLASS] != ELFCLASS64 || header.e_ident[EI_DATA] != ELFDATA2LSB ||
+                header.e_machine != EM_X86_64 {
+            return Err(SyscallError::FsNotExecutable);
+        }
+        let bias = match header.e_type {
+            ET_EXEC => 0,
+            ET_DYN => pie_base,
+            _ => return Err(SyscallError::FsNotExecutable),
+        };
+        if header.e_phnum > 0 && header.e_phentsize as usize != size_of::<ProgramHeader>() {
+            return Err(SyscallError::ExecMalformed);
         }
-        match (header.e_phoff as usize).checked_add(header.e_phnum as usize * core::mem::size_of::<ProgramHeader>()) {
-            Some(x) if (x as usize) < data.len() => {},
-            _ => return Err("Invalid program headers")
+        match (header.e_phoff as usize).checked_add(header.e_phnum as usize * size_of::<ProgramHeader>()) {
+            Some(x) if x <= data.len() => {},
+            _ => return Err(SyscallError::ExecMalformed),
+        }
+        // program_headers() points into the file, the entries must be aligned
+        if (data.as_ptr() as usize).wrapping_add(header.e_phoff as usize) % align_of::<ProgramHeader>() != 0 {
+            return Err(SyscallError::ExecMalformed);
+        }
+        // programs() slices the file with these
+        for header in elf.program_headers() {
+            match header.p_offset.checked_add(header.p_filesz) {
+                Some(x) if x <= data.len() as u64 => {},
+                _ => return Err(SyscallError::ExecMalformed),
+            }
         }
+        let entry = bias.checked_add(header.e_entry).ok_or(SyscallError::ExecAddressNotUserspace)?;
+        check_user_range(entry, 1)?;
+        elf.bias = bias;
         Ok(elf)
     }
 
+    /// Address of the first instruction, once loaded
+    pub fn entry_point(&self) -> u64 {
+        self.bias.wrapping_add(self.header().e_entry)
+    }
+
+    /// How much the program is moved from the addresses it's linked at
+    pub fn load_bias(&self) -> u64 {
+        self.bias
+    }
+
+    /// Path of the loader that the program needs (PT_INTERP)
+    pub fn interpreter(&self) -> SyscallResult<Option<&str>> {
+        let data = match self.programs().find(|(x, _)| x.p_type == PT_INTERP) {
+            Some((_, data)) => data,
+            None => return Ok(None),
+        };
+        // The path is null-terminated
+        let path = data.split(|x| *x == 0).next().unwrap_or(&[]);
+        match core::str::from_utf8(path) {
+            Ok(x) if !x.is_empty() => Ok(Some(x)),
+            _ => Err(SyscallError::ExecMalformed),
+        }
+    }
+
+    fn has_interpreter(&self) -> bool {
+        self.program_headers().iter().any(|x| x.p_type == PT_INTERP)
+    }
+
     pub fn header(&self) -> &Header {
         let header_ref = self.data[..SIZEOF_EHDR].try_into().unwrap();
         Header::from_bytes(header_ref)
@@ -53,55 +129,355 @@ impl<'a> Elf<'a> {
         })
     }
 
-    pub fn mount_into(&self, table: &mut OffsetPageTable) {
-        let mut allocator = get_frame_allocator();
-        let offset = physical_memory_offset();
+    /// Where the program headers are once the program is loaded (None if no segment contains them)
+    pub fn program_headers_address(&self) -> Option<u64> {
+        let headers = self.program_headers();
+        if let Some(x) = headers.iter().find(|x| x.p_type == PT_PHDR) {
+            return Some(self.bias.wrapping_add(x.p_vaddr));
+        }
+        let offset = self.header().e_phoff;
+        headers.iter()
+            .filter(|x| x.p_type == PT_LOAD)
+            .find(|x| x.p_offset <= offset && offset - x.p_offset < x.p_filesz)
+            .map(|x| self.bias.wrapping_add(x.p_vaddr) + (offset - x.p_offset))
+    }
+
+    /// Contents in the file of [addr, addr + len) (addr not biased), if a segment contains them
+    fn file_range(&self, addr: u64, len: u64) -> Option<&[u8]> {
+        let end = addr.checked_add(len)?;
+        self.loadable_segments()
+            .find(|(x, _)| x.p_vaddr <= addr && end - x.p_vaddr <= x.p_filesz)
+            .map(|(x, data)| &data[(addr - x.p_vaddr) as usize..][..len as usize])
+    }
+
+    fn loadable_segments(&'a self) -> impl Iterator<Item=(&'a ProgramHeader, &'a [u8])> + 'a {
+        self.programs()
+            .filter(|(x, _)| x.p_type == PT_LOAD)
+            .filter(|(x, _)| x.p_memsz > 0)
+    }
+
+    /// Checks the loadable segments before anything gets mapped
+    fn check_segments(&self) -> SyscallResult<()> {
+        // Pages used by each segment, [start, end)
+        let mut ranges = Vec::new();
+        for (header, _) in self.loadable_segments() {
+            if header.p_filesz > header.p_memsz {
+                return Err(SyscallError::ExecMalformed);
+            }
+            // Pages are always readable on x86, the program asked for something we can't do
+            if header.p_flags & PF_R == 0 {
+                return Err(SyscallError::ExecMalformed);
+            }
+            // The file offset and the address must be congruent modulo the alignment
+            let align = header.p_align;
+            if align > 1 && (!align.is_power_of_two() || header.p_vaddr.wrapping_sub(header.p_offset) & (align - 1) != 0) {
+                return Err(SyscallError::ExecMalformed);
+            }
+            let start = self.bias.checked_add(header.p_vaddr).ok_or(SyscallError::ExecAddressNotUserspace)?;
+            let end = check_user_range(start, header.p_memsz)?;
+            ranges.try_reserve(1)?;
+            ranges.push((align_down(start), align_up(end)));
+        }
+
+        // Every page gets its own frame, segments can't share them
+        ranges.sort_unstable();
+        if ranges.windows(2).any(|x| x[0].1 > x[1].0) {
+            return Err(SyscallError::ExecSegmentsOverlap);
+        }
+        Ok(())
+    }
+
+    /// Maps the program (and its interpreter, if it needs one) with the TLS block of the initial
+    /// thread, returns the thread pointer that FS should point to (if the program uses TLS).
+    /// If loading fails every page it mapped is unmapped again (with the tables left empty)
+    pub fn load(&self, interpreter: Option<&Elf>, table: &mut UserPageTable) -> SyscallResult<Option<VirtAddr>> {
+        self.check_segments()?;
+        if let Some(x) = interpreter {
+            x.check_segments()?;
+        }
+        let mut mapped = Vec::new();
+        let result = {
+            let mut offset_table = table.offset_page();
+            self.map_program(&mut offset_table, &mut mapped)
+                    .and_then(|thread_pointer| match interpreter {
+                        Some(x) => x.map_program(&mut offset_table, &mut mapped),
+                        None => Ok(thread_pointer),
+                    })
+        };
+        if result.is_err() {
+            for page in mapped {
+                table.unmap(Page::range(page, page + 1));
+            }
+        }
+        result
+    }
 
-        let headers = self.programs()
-                .filter(|(x, _)| x.p_type == PT_LOAD)
-                .filter(|(x, _)| x.p_memsz > 0);
-        for (header, data) in headers {
-            let from = VirtAddr::new(header.p_vaddr);
-            let to = from + header.p_memsz;
-            check_addr_userspace(from.as_u64() as usize).unwrap();
-            check_addr_userspace(to.as_u64() as usize).unwrap();
-            let from_page = Page::containing_address(from);
-            let to_page = Page::containing_address(to - 1usize);
-            let range = Page::<Size4KiB>::range_inclusive(from_page, to_page);
+    fn map_program(&self, table: &mut OffsetPageTable, mapped: &mut Vec<Page>) -> SyscallResult<Option<VirtAddr>> {
+        self.mount_into(table, mapped)?;
+        // The loader relocates the program and sets up its TLS
+        if self.has_interpreter() {
+            return Ok(None);
+        }
+        self.relocate(table)?;
+        self.mount_tls(table, mapped)
+    }
 
+    fn mount_into(&self, table: &mut OffsetPageTable, mapped: &mut Vec<Page>) -> SyscallResult<()> {
+        for (header, data) in self.loadable_segments() {
             let mut flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
             if header.p_flags & PF_X == 0 { flags |= PageTableFlags::NO_EXECUTE; }
             if header.p_flags & PF_W != 0 { flags |= PageTableFlags::WRITABLE; }
-            if header.p_flags & PF_R == 0 { panic!("Program section not readable?") }
             let flags = flags;// not mutable anymore
 
-            // index into the data array
-            let file_index = from_page.start_address().as_u64() as isize - from.as_u64() as isize;
-            for page in range {
-                let frame = allocator.allocate_frame().expect("Cannot get frame for init");
-
-                unsafe {
-                    table.map_to(page, frame, flags, &mut *allocator)
-                            .expect("Failed to map program")
-                            .flush();
-
-                    let ptr = (offset + frame.start_address().as_u64()).as_mut_ptr() as *mut [u8; Size4KiB::SIZE as usize];
-                    ptr.write([0; Size4KiB::SIZE as usize]);
-
-                    let data_start = max(file_index, 0);
-                    let data_end = min(file_index + Size4KiB::SIZE as isize, data.len() as isize);
-
-                    // offset to the page,
-                    let off = max(-file_index, 0);
-                    if data_start <= data_end {
-                        copy_nonoverlapping(
-                            data.as_ptr().offset(data_start),
-                            (offset + frame.start_address().as_u64()).as_mut_ptr::<u8>().offset(off),
-                            (data_end - data_start) as usize
-                        );
+            map_region(table, VirtAddr::new(self.bias + header.p_vaddr), header.p_memsz, data, flags, mapped)?;
+        }
+        Ok(())
+    }
+
+    /// Applies the relocations of a position-independent program, a static one only needs
+    /// R_X86_64_RELATIVE (write the load bias + addend)
+    fn relocate(&self, table: &mut OffsetPageTable) -> SyscallResult<()> {
+        if self.header().e_type != ET_DYN {
+            return Ok(());
+        }
+        let dynamic = match self.programs().find(|(x, _)| x.p_type == PT_DYNAMIC) {
+            Some((_, data)) => data,
+            None => return Ok(()),
+        };
+
+        let (mut rela, mut rela_size, mut rela_entry) = (None, 0, SIZEOF_RELA as u64);
+        for entry in dynamic.chunks_exact(size_of::<Dyn>()) {
+            let entry = unsafe { read_unaligned(entry.as_ptr() as *const Dyn) };
+            match entry.d_tag {
+                DT_NULL => break,
+                DT_RELA => rela = Some(entry.d_val),
+                DT_RELASZ => rela_size = entry.d_val,
+                DT_RELAENT => rela_entry = entry.d_val,
+                // Libraries or relocation formats we can't handle
+                DT_NEEDED | DT_REL | DT_RELR => return Err(SyscallError::ExecRelocationUnsupported),
+                _ => {},
+            }
+        }
+        let rela = match rela {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+        if rela_entry != SIZEOF_RELA as u64 {
+            return Err(SyscallError::ExecMalformed);
+        }
+
+        let relocations = self.file_range(rela, rela_size).ok_or(SyscallError::ExecMalformed)?;
+        for x in relocations.chunks_exact(SIZEOF_RELA) {
+            let relocation = unsafe { read_unaligned(x.as_ptr() as *const Rela) };
+            match r_type(relocation.r_info) {
+                R_X86_64_NONE => {},
+                R_X86_64_RELATIVE => {
+                    let value = self.bias.wrapping_add(relocation.r_addend as u64);
+                    self.write_u64(table, relocation.r_offset, value)?;
+                }
+                _ => return Err(SyscallError::ExecRelocationUnsupported),
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes a word of the loaded program, addr (not biased) must be inside of a segment
+    fn write_u64(&self, table: &mut OffsetPageTable, addr: u64, value: u64) -> SyscallResult<()> {
+        let inside = self.loadable_segments()
+                .any(|(x, _)| x.p_vaddr <= addr && addr.checked_add(8).map_or(false, |end| end - x.p_vaddr <= x.p_memsz));
+        if !inside {
+            return Err(SyscallError::ExecMalformed);
+        }
+        let addr = self.bias + addr;
+        let bytes = value.to_le_bytes();
+        // The word might cross a page boundary
+        let split = min(bytes.len() as u64, Size4KiB::SIZE - addr % Size4KiB::SIZE) as usize;
+        for (at, part) in [(addr, &bytes[..split]), (addr + split as u64, &bytes[split..])] {
+            if part.is_empty() {
+                continue;
+            }
+            let phys = table.translate_addr(VirtAddr::new(at)).ok_or(SyscallError::MemoryNotMapped)?;
+            unsafe {
+                copy_nonoverlapping(part.as_ptr(), (physical_memory_offset() + phys.as_u64()).as_mut_ptr::<u8>(), part.len());
+            }
+        }
+        Ok(())
+    }
+
+    fn mount_tls(&self, table: &mut OffsetPageTable, mapped: &mut Vec<Page>) -> SyscallResult<Option<VirtAddr>> {
+        let (header, data) = match self.programs().find(|(x, _)| x.p_type == PT_TLS) {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+        // The TCB holds pointers, don't misalign it
+        let align = max(header.p_align, TLS_TCB_ALIGN);
+        if !align.is_power_of_two() || align > Size4KiB::SIZE || header.p_memsz > TLS_MAX_SIZE ||
+                header.p_filesz > header.p_memsz {
+            return Err(SyscallError::ExecMalformed);
+        }
+
+        // Variant II: the block ends right before the TCB, FS points to the TCB
+        let block_size = (header.p_memsz + align - 1) & !(align - 1);
+        let start = VirtAddr::new(USERSPACE_TLS_ADDR);
+        let thread_pointer = start + block_size;
+        let flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE
+                | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        map_region(table, start, block_size + TLS_TCB_SIZE, data, flags, mapped)?;
+
+        // The first word of the TCB points to itself, that's how programs read FS
+        let tcb = table.translate_addr(thread_pointer).ok_or(SyscallError::MemoryNotMapped)?;
+        unsafe {
+            *(physical_memory_offset() + tcb.as_u64()).as_mut_ptr::<u64>() = thread_pointer.as_u64();
+        }
+        Ok(Some(thread_pointer))
+    }
+}
+
+fn align_down(x: u64) -> u64 {
+    x & !(Size4KiB::SIZE - 1)
+}
+
+fn align_up(x: u64) -> u64 {
+    align_down(x + Size4KiB::SIZE - 1)
+}
+
+/// Checks that [from, from + len) is a valid userspace range, returns its end
+fn check_user_range(from: u64, len: u64) -> SyscallResult<u64> {
+    let end = from.checked_add(len).ok_or(SyscallError::ExecMalformed)?;
+    let last = end.saturating_sub(1);
+    // Userspace addresses are canonical by construction, but check_addr_userspace only looks at the top bit
+    for x in [from, last] {
+        check_addr_userspace(x as usize).map_err(|_| SyscallError::ExecAddressNotUserspace)?;
+        VirtAddr::try_new(x).map_err(|_| SyscallError::ExecAddressNotUserspace)?;
+    }
+    Ok(end)
+}
+
+fn map_error(error: MapToError<Size4KiB>) -> SyscallError {
+    match error {
+        MapToError::PageAlreadyMapped(_) | MapToError::ParentEntryHugePage => SyscallError::MemoryAlreadyMapped,
+        MapToError::FrameAllocationFailed => SyscallError::NoMemory,
+    }
+}
+
+/// Maps [from, from + len) to new frames, copying data at its start and zeroing the rest.
+/// The mapped pages are added to mapped, even if it fails halfway
+fn map_region(table: &mut OffsetPageTable, from: VirtAddr, len: u64, data: &[u8], flags: PageTableFlags, mapped: &mut Vec<Page>) -> SyscallResult<()> {
+    let mut allocator = get_frame_allocator();
+    let offset = physical_memory_offset();
+
+    let from_page = Page::containing_address(from);
+    let to_page = Page::containing_address(from + len - 1u64);
+    let range = Page::<Size4KiB>::range_inclusive(from_page, to_page);
+
+    // index into the data array
+    let file_index = from_page.start_address().as_u64() as isize - from.as_u64() as isize;
+    for (i, page) in range.enumerate() {
+        mapped.try_reserve(1)?;
+        let frame = allocator.allocate_frame().ok_or(SyscallError::NoMemory)?;
+        let file_index = file_index + i as isize * Size4KiB::SIZE as isize;
+
+        unsafe {
+            // BIT_10: the frame belongs to the mapping, unmapping the page frees it
+            match table.map_to(page, frame, flags | PageTableFlags::BIT_10, &mut *allocator) {
+                Ok(flush) => flush.flush(),
+                Err(e) => {
+                    allocator.deallocate_frame(frame);
+                    // The tables allocated before running out of memory must be freed too
+                    if let MapToError::FrameAllocationFailed = e {
+                        mapped.push(page);
                     }
+                    return Err(map_error(e));
                 }
             }
+            mapped.push(page);
+
+            let ptr = (offset + frame.start_address().as_u64()).as_mut_ptr() as *mut [u8; Size4KiB::SIZE as usize];
+            ptr.write([0; Size4KiB::SIZE as usize]);
+
+            let data_start = max(file_index, 0);
+            let data_end = min(file_index + Size4KiB::SIZE as isize, data.len() as isize);
+
+            // offset to the page,
+            let off = max(-file_index, 0);
+            if data_start <= data_end {
+                copy_nonoverlapping(
+                    data.as_ptr().offset(data_start),
+                    (offset + frame.start_address().as_u64()).as_mut_ptr::<u8>().offset(off),
+                    (data_end - data_start) as usize
+                );
+            }
         }
     }
+    Ok(())
+}
+
+// A program with one segment, the file ends after its headers
+#[cfg(test)]
+#[repr(C)]
+pub(crate) struct TestProgram {
+    header: Header,
+    segment: ProgramHeader,
+    padding: [u8; 8],
+}
+
+#[cfg(test)]
+pub(crate) fn test_program(segment: ProgramHeader) -> TestProgram {
+    let mut header = Header::default();
+    header.e_ident[..SELFMAG].copy_from_slice(ELFMAG);
+    header.e_ident[EI_CLASS] = ELFCLASS64;
+    header.e_ident[EI_DATA] = ELFDATA2LSB;
+    header.e_type = ET_EXEC;
+    header.e_machine = EM_X86_64;
+    header.e_entry = 0x40_0000;
+    header.e_phoff = SIZEOF_EHDR as u64;
+    header.e_phentsize = size_of::<ProgramHeader>() as u16;
+    header.e_phnum = 1;
+    TestProgram { header, segment, padding: [0; 8] }
+}
+
+#[cfg(test)]
+pub(crate) fn test_segment(vaddr: u64, offset: u64, align: u64, flags: u32) -> ProgramHeader {
+    ProgramHeader {
+        p_type: PT_LOAD,
+        p_flags: flags,
+        p_offset: offset,
+        p_vaddr: vaddr,
+        p_paddr: vaddr,
+        p_filesz: 0x10,
+        p_memsz: 0x1000,
+        p_align: align,
+    }
+}
+
+#[cfg(test)]
+pub(crate) fn test_program_bytes(program: &TestProgram) -> &[u8] {
+    unsafe {
+        slice::from_raw_parts(program as *const TestProgram as *const u8, size_of::<TestProgram>())
+    }
+}
+
+#[cfg(test)]
+fn check_test_program(program: &TestProgram) -> SyscallResult<()> {
+    Elf::new(test_program_bytes(program), 0)?.check_segments()
+}
+
+#[test_case]
+fn elf_segments_rejected() {
+    let check = |segment| check_test_program(&test_program(segment));
+    let mut larger_file = test_segment(0x40_0000, 0, 0x1000, PF_R);
+    larger_file.p_filesz = 0x2000;
+    assert_eq!(check(larger_file), Err(SyscallError::ExecMalformed));
+    assert_eq!(check(test_segment(0x40_0000, 0, 0x1000, PF_W)), Err(SyscallError::ExecMalformed));
+    assert_eq!(check(test_segment(0x40_0010, 0, 0x1000, PF_R)), Err(SyscallError::ExecMalformed));
+    assert_eq!(check(test_segment(0x40_0000, 0, 0x1800, PF_R)), Err(SyscallError::ExecMalformed));
+    assert_eq!(check(test_segment(0xFFFF_8000_0000_0000, 0, 0x1000, PF_R)), Err(SyscallError::ExecAddressNotUserspace));
+    assert_eq!(check(test_segment(0x7FFF_FFFF_F800, 0, 0x800, PF_R)), Err(SyscallError::ExecAddressNotUserspace));
+}
+
+#[test_case]
+fn elf_misaligned_program_headers_rejected() {
+    let mut program = test_program(test_segment(0x40_0000, 0, 0x1000, PF_R));
+    program.header.e_phoff += 1;
+    assert_eq!(check_test_program(&program).err(), Some(SyscallError::ExecMalformed));
 }
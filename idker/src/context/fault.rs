@@ -0,0 +1,190 @@
+use core::{convert::TryFrom, mem::size_of};
+
+use alloc::{sync::Arc, vec::Vec};
+use spin::RwLock;
+use syscall::{FaultAction, FaultKind, FaultMessage, FaultRegisters, FaultReply, SyscallError, SyscallResult};
+use x86_64::registers::rflags::RFlags;
+
+use crate::{capability::{CapabilityPerms, CapabilityType, syscall::{CapabilityHandle, TaskCapabilityStorage}}, conversation::{ConversationKind, Message}, interrupts::ExceptionFrame, syscalls::asm::AllSavedRegisters};
+
+use super::{TaskContext, current_process, current_process_id, current_task, syscall::self_or_child};
+
+// Exception ports: a task can have a conversation endpoint where its faults are reported.
+// The faulting task is stopped until the other side of the conversation answers, so a userspace
+// debugger (or pager, or sandbox) can inspect it, fix it and resume it.
+
+// Rflags bits that a fault port can change
+const USER_RFLAGS: RFlags = RFlags::from_bits_truncate(
+    RFlags::CARRY_FLAG.bits() | RFlags::PARITY_FLAG.bits() | RFlags::AUXILIARY_CARRY_FLAG.bits() |
+    RFlags::ZERO_FLAG.bits() | RFlags::SIGN_FLAG.bits() | RFlags::TRAP_FLAG.bits() |
+    RFlags::DIRECTION_FLAG.bits() | RFlags::OVERFLOW_FLAG.bits()
+);
+// First non-canonical address of the lower half
+const USERSPACE_END: u64 = 1 << 47;
+
+/// Fault ports belong to processes, pid 0 is the current process
+fn target_process(pid: usize) -> SyscallResult<Arc<RwLock<TaskContext>>> {
+    match pid {
+        0 => Ok(current_process()),
+        x => self_or_child(x),
+    }
+}
+
+pub fn set_port(pid: usize, handle: CapabilityHandle) -> SyscallResult<()> {
+    let target_lock = target_process(pid)?;
+
+    let target_caps = target_lock.read().capabilities.clone();
+
+    let endpoint = {
+        let current_lock = current_task();
+        let current = current_lock.read();
+        let mut caps = current.capabilities.lock();
+        let cap = caps.get(handle).ok_or(SyscallError::WrongCapability)?;
+        // The kernel must be the only one listening on its side
+        let endpoint = match &cap.ctype {
+            CapabilityType::Conversation(x) if x.kind() == ConversationKind::P2p => x,
+            _ => return Err(SyscallError::WrongCapability),
+        };
+        if !cap.perms.contains(CapabilityPerms::TRANSFER) {
+            return Err(SyscallError::WrongCapabilityPerms);
+        }
+        // A faulting process is stopped, it can't answer for itself.
+        // The target is the current process or one of its children, so the locks are always
+        // taken parent first
+        let serves_itself = |caps: &TaskCapabilityStorage| caps.handles.iter()
+            .any(|(_, x)| matches!(&x.ctype, CapabilityType::Conversation(y) if y.is_peer_of(endpoint)));
+        let self_served = if Arc::ptr_eq(&target_caps, &current.capabilities) {
+            serves_itself(&caps)
+        } else {
+            serves_itself(&target_caps.lock())
+        };
+        if self_served {
+            return Err(SyscallError::FaultPortSelfServed);
+        }
+        match caps.delete(handle)?.ctype {
+            CapabilityType::Conversation(x) => x,
+            _ => unreachable!(),
+        }
+    };
+    // The reply is read by the kernel, capabilities in it would be lost
+    endpoint.refuse_capabilities();
+
+    let old_port = target_lock.write().fault_port.replace(endpoint);
+    drop(old_port);
+    Ok(())
+}
+
+pub fn clear_port(pid: usize) -> SyscallResult<()> {
+    let target_lock = target_process(pid)?;
+    let old_port = target_lock.write().fault_port.take();
+    drop(old_port);
+    Ok(())
+}
+
+/// Reports a fault of the current task to its fault port and waits for the answer.
+/// Returns false if the task should be killed (no port, closed port, wrong answer...)
+pub fn deliver_fault(regs: &mut AllSavedRegisters, frame: &mut ExceptionFrame, kind: FaultKind, cr2: u64) -> bool {
+    let port = {
+        let task_lock = current_process();
+        let task = task_lock.read();
+        match &task.fault_port {
+            Some(x) => x.clone(),
+            None => return false,
+        }
+    };
+
+    let message = FaultMessage {
+        pid: current_process_id().0.get(),
+        vector: kind.vector(),
+        error_code: frame.error_code,
+        cr2,
+        regs: read_registers(regs, frame),
+    };
+    let mut data = Vec::new();
+    if data.try_reserve_exact(size_of::<FaultMessage>()).is_err() {
+        return false;
+    }
+    data.extend_from_slice(message.as_bytes());
+
+    let sent = port.send(Message {
+        sender: current_process_id(),
+        data,
+        capabilities: Vec::new(),
+    });
+    if sent.is_err() {
+        return false;
+    }
+
+    let reply = loop {
+        match port.receive(size_of::<FaultReply>(), 0) {
+            Ok(x) => break x,
+            Err(SyscallError::ConversationEmpty) => {
+                if port.wait_message().is_err() {
+                    return false;
+                }
+            }
+            Err(_) => return false,
+        }
+    };
+
+    let reply = match FaultReply::from_bytes(&reply.data) {
+        Some(x) => x,
+        None => return false,
+    };
+    match FaultAction::try_from(reply.action) {
+        Ok(FaultAction::Resume) => true,
+        Ok(FaultAction::SetRegisters) => write_registers(regs, frame, &reply.regs),
+        Ok(FaultAction::Kill) | Err(_) => false,
+    }
+}
+
+fn read_registers(regs: &AllSavedRegisters, frame: &ExceptionFrame) -> FaultRegisters {
+    FaultRegisters {
+        rax: regs.rax as u64,
+        rbx: regs.rbx as u64,
+        rcx: regs.rcx as u64,
+        rdx: regs.rdx as u64,
+        rbp: regs.rbp as u64,
+        rsi: regs.rsi as u64,
+        rdi: regs.rdi as u64,
+        r8: regs.r8 as u64,
+        r9: regs.r9 as u64,
+        r10: regs.r10 as u64,
+        r11: regs.r11 as u64,
+        r12: regs.r12 as u64,
+        r13: regs.r13 as u64,
+        r14: regs.r14 as u64,
+        r15: regs.r15 as u64,
+        rip: frame.frame.instruction_pointer.as_u64(),
+        rsp: frame.frame.stack_pointer.as_u64(),
+        rflags: frame.frame.cpu_flags,
+    }
+}
+
+/// Returns false if the registers cannot be used to go back to userspace
+fn write_registers(regs: &mut AllSavedRegisters, frame: &mut ExceptionFrame, new: &FaultRegisters) -> bool {
+    // iretq would fault in the kernel with a non-canonical address
+    if new.rip >= USERSPACE_END || new.rsp >= USERSPACE_END {
+        return false;
+    }
+
+    regs.rax = new.rax as usize;
+    regs.rbx = new.rbx as usize;
+    regs.rcx = new.rcx as usize;
+    regs.rdx = new.rdx as usize;
+    regs.rbp = new.rbp as usize;
+    regs.rsi = new.rsi as usize;
+    regs.rdi = new.rdi as usize;
+    regs.r8 = new.r8 as usize;
+    regs.r9 = new.r9 as usize;
+    regs.r10 = new.r10 as usize;
+    regs.r11 = new.r11 as usize;
+    regs.r12 = new.r12 as usize;
+    regs.r13 = new.r13 as usize;
+    regs.r14 = new.r14 as usize;
+    regs.r15 = new.r15 as usize;
+    frame.frame.instruction_pointer = x86_64::VirtAddr::new(new.rip);
+    frame.frame.stack_pointer = x86_64::VirtAddr::new(new.rsp);
+    frame.frame.cpu_flags = (frame.frame.cpu_flags & !USER_RFLAGS.bits()) | (new.rflags & USER_RFLAGS.bits());
+    true
+}
@@ -0,0 +1,166 @@
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+use alloc::{collections::{BTreeMap, VecDeque}, sync::Arc};
+use lazy_static::lazy_static;
+use spin::Mutex;
+use syscall::{SyscallError, SyscallResult};
+use x86_64::{PhysAddr, VirtAddr, structures::paging::Translate};
+
+use crate::{arch::paging::physical_memory_offset, syscalls::user_virt_addr, time::{Timer, monotonic_ns}};
+
+use super::{UserPageTable, current_task, wait_queue::WaitQueue};
+
+// Futexes let userspace sleep on a word of its own memory: locks are taken with atomics and the
+// kernel is only called when someone has to wait.
+// They are keyed by physical address so that processes sharing memory can use them, the queue of
+// an address only exists while some task is waiting on it.
+// Wakers take the tasks they wake out of the queue, a woken task returns right away without
+// looking at the word again, so no wake up is lost and the count returned by wake is exact.
+
+struct Waiter {
+    // Only this task waits here, the timeout timer wakes it too
+    queue: Arc<WaitQueue>,
+    woken: AtomicBool,
+}
+
+lazy_static! {
+    static ref FUTEXES: Mutex<BTreeMap<PhysAddr, VecDeque<Arc<Waiter>>>> = Mutex::new(BTreeMap::new());
+}
+
+/// Checks the address of a futex word, the whole word must be in userspace
+fn word_addr(addr: usize) -> SyscallResult<VirtAddr> {
+    if addr % 4 != 0 {
+        return Err(SyscallError::WrongParameters);
+    }
+    // Aligned, so the word can't cross the end of userspace
+    user_virt_addr(addr)
+}
+
+/// Physical address of a futex word in user_table
+fn translate(user_table: &mut UserPageTable, addr: usize) -> SyscallResult<PhysAddr> {
+    let addr = word_addr(addr)?;
+    let phys = user_table.offset_page().translate_addr(addr);
+    phys.ok_or(SyscallError::MemoryNotMapped)
+}
+
+/// Takes waiter out of the queue of key, returns false if a waker already did
+fn dequeue(key: PhysAddr, waiter: &Arc<Waiter>) -> bool {
+    let mut futexes = FUTEXES.lock();
+    let queue = match futexes.get_mut(&key) {
+        Some(x) => x,
+        None => return false,
+    };
+    let index = match queue.iter().position(|x| Arc::ptr_eq(x, waiter)) {
+        Some(x) => x,
+        None => return false,
+    };
+    queue.remove(index);
+    if queue.is_empty() {
+        futexes.remove(&key);
+    }
+    true
+}
+
+pub fn wait(addr: usize, expected: usize, timeout: usize) -> SyscallResult<()> {
+    let waiter = Arc::new(Waiter {
+        queue: Arc::new(WaitQueue::new()),
+        woken: AtomicBool::new(false),
+    });
+
+    let key = {
+        // The page can't be unmapped (and its frame reused) until we're queued on it
+        let task_lock = current_task();
+        let task = task_lock.read();
+        let mut user_table = task.page_table.lock();
+        let key = translate(&mut user_table, addr)?;
+        let word = unsafe { &*(physical_memory_offset() + key.as_u64()).as_ptr::<AtomicU32>() };
+
+        // Checked with the futexes locked, like the wakers do
+        let mut futexes = FUTEXES.lock();
+        if word.load(Ordering::SeqCst) != expected as u32 {
+            return Err(SyscallError::FutexValueChanged);
+        }
+        let queue = futexes.entry(key).or_insert_with(VecDeque::new);
+        if let Err(e) = queue.try_reserve(1) {
+            if queue.is_empty() {
+                futexes.remove(&key);
+            }
+            return Err(e.into());
+        }
+        queue.push_back(waiter.clone());
+        key
+    };
+
+    let timer = match timeout {
+        0 => None,
+        x => {
+            let timer = Timer::notifying(&waiter.queue);
+            if let Err(e) = timer.arm(monotonic_ns().saturating_add(x as u64)) {
+                if dequeue(key, &waiter) {
+                    return Err(e);
+                }
+                // Woken in the meantime
+                return Ok(());
+            }
+            Some(timer)
+        }
+    };
+
+    let waited = waiter.queue.wait_while(|| {
+        !waiter.woken.load(Ordering::SeqCst) && !timer.as_ref().map_or(false, |x| x.has_fired())
+    });
+    if let Some(x) = timer {
+        x.cancel();
+    }
+
+    // Still queued: nobody woke us up
+    if dequeue(key, &waiter) {
+        waited?;
+        return Err(SyscallError::TimedOut);
+    }
+    waited
+}
+
+pub fn wake(addr: usize, count: usize) -> SyscallResult<usize> {
+    let key = {
+        let task_lock = current_task();
+        let task = task_lock.read();
+        let mut user_table = task.page_table.lock();
+        translate(&mut user_table, addr)?
+    };
+
+    let mut woken = 0;
+    while woken < count {
+        let waiter = {
+            let mut futexes = FUTEXES.lock();
+            let queue = match futexes.get_mut(&key) {
+                Some(x) => x,
+                None => break,// Nobody is waiting
+            };
+            let waiter = queue.pop_front();
+            if queue.is_empty() {
+                futexes.remove(&key);
+            }
+            match waiter {
+                Some(x) => x,
+                None => break,
+            }
+        };
+        // Out of the queue it will return even if it's not sleeping yet
+        waiter.woken.store(true, Ordering::SeqCst);
+        waiter.queue.wake_all();
+        woken += 1;
+    }
+    Ok(woken)
+}
+
+#[test_case]
+fn futex_word_addr_in_userspace() {
+    assert_eq!(word_addr(0x1000).map(|x| x.as_u64()), Ok(0x1000));
+    assert_eq!(word_addr(0x7FFF_FFFF_FFFC).map(|x| x.as_u64()), Ok(0x7FFF_FFFF_FFFC));
+    assert_eq!(word_addr(0x1002), Err(SyscallError::WrongParameters));
+    // Bit 47 would be sign-extended into the kernel half
+    assert_eq!(word_addr(0x0000_8000_0000_1000), Err(SyscallError::WrongParameters));
+    assert_eq!(word_addr(0x0001_0000_0000_0000), Err(SyscallError::WrongParameters));
+    assert_eq!(word_addr(0xFFFF_8000_0000_1000), Err(SyscallError::WrongParameters));
+}
@@ -1,19 +1,25 @@
 pub mod task;
+pub mod fault;
+pub mod futex;
 pub mod elf;
 pub mod init;
 pub mod page_table;
 pub mod registry;
+pub mod scheduler;
 pub mod switch;
 pub mod syscall;
+pub mod wait_queue;
 
-use core::{cell::Cell, num::NonZeroU64, sync::atomic::{AtomicU64, Ordering}};
+use core::{cell::Cell, mem, num::NonZeroU64, sync::atomic::{AtomicU64, Ordering}};
 
-use alloc::sync::Arc;
+use alloc::{sync::Arc, vec::Vec};
+use ::syscall::ExitReason;
 use spin::{Once, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use x86_64::{VirtAddr, registers::model_specific::KernelGsBase};
 pub use task::{TaskId, TaskContext};
 pub use page_table::UserPageTable;
 
-use crate::{context::task::TaskState};
+use crate::{arch::{cpu, tlb}, context::task::{TaskState, TIME_SLICE_TICKS}, gdt, syscalls::TCD, time};
 
 use self::{registry::TaskRegistry, switch::switch_task};
 
@@ -22,6 +28,10 @@ static TASKS: Once<RwLock<TaskRegistry>> = Once::new();
 #[thread_local]
 static TASK_ID: AtomicU64 = AtomicU64::new(0);
 
+// Task that runs when this core has nothing else to do
+#[thread_local]
+static IDLE_TASK_ID: AtomicU64 = AtomicU64::new(0);
+
 #[thread_local]
 static TASK_SWITCH_LOCKS: Cell<Option<[Arc<RwLock<TaskContext>>; 2]>> = Cell::new(None);
 
@@ -51,22 +61,37 @@ pub fn current_task() -> Arc<RwLock<TaskContext>> {
         .clone()
 }
 
+/// Id of the process of the current task (the id of its first thread)
+pub fn current_process_id() -> TaskId {
+    current_task().read().process
+}
+
+/// The first thread of the current process, it holds the process relationships
+pub fn current_process() -> Arc<RwLock<TaskContext>> {
+    let process = current_process_id();
+    tasks().get(process)
+        .expect("Current process not in registry")
+        .clone()
+}
+
 pub fn switch_to_next_task() -> bool {
-    let mut tasks = tasks_mut();
+    let tasks = tasks();
 
     let from = current_task_id();
 
     let fctx = tasks.get(from).unwrap().clone();
     let from_task = fctx.write();
 
-    if from_task.state == TaskState::User {
-        tasks.executable_tasks.push_back(from);
-    }
-
-    let next = tasks.executable_tasks.pop_front();
-
-    // task 1 will wait for interrupts
-    let next = next.unwrap_or_else(|| TaskId(NonZeroU64::new(1).unwrap()));
+    // The from task is queued again only after it's been switched out (see after_task_switch),
+    // otherwise another core could pick it while its context is still being saved
+    let can_stay = from_task.state == TaskState::User && from_task.affinity & (1 << cpu::cpu_id()) != 0;
+    // A task that can keep running is only replaced by one that is at least as important
+    let next = match scheduler::dequeue(can_stay.then(|| from_task.priority)) {
+        Some(x) => x,
+        None if can_stay => return false,
+        // The idle task will wait for interrupts
+        None => idle_task_id(),
+    };
     if next == from {
         return false;
     }
@@ -79,12 +104,121 @@ pub fn switch_to_next_task() -> bool {
     set_current_task_id(next);
     TASK_SWITCH_LOCKS.set(Some([fctx, tctx]));
     unsafe {
+        (*flock).tcd = TCD;
+        TCD = (*tlock).tcd;
+        (*flock).fpu.save();
+        (*tlock).fpu.restore();
+        // swapgs will give it to userspace
+        KernelGsBase::write(VirtAddr::new(TCD.user_gs_base));
+        (*tlock).time_slice = TIME_SLICE_TICKS;
+        (*tlock).on_cpu = true;
+        if let Some(stack_end) = (*tlock).kernel_stack_end() {
+            gdt::set_user_interrupt_stack(stack_end);
+        }
+        tlb::set_active_table((*tlock).arch_regs.cr3 as u64);
+
         // Here we should hold no locks except for the from and to tasks (that we forgot)
         switch_task(&(& *flock).arch_regs, &(& *tlock).arch_regs);
     }
     return true;
 }
 
+/// Called by the timer when it interrupts userspace, switches task when the time slice ends
+/// or when a more important task is waiting
+pub fn on_user_tick() {
+    let (expired, priority) = {
+        let task_lock = current_task();
+        let mut task = task_lock.write();
+        task.time_slice = task.time_slice.saturating_sub(1);
+        (task.time_slice == 0, task.priority)
+    };
+    if expired || scheduler::has_more_important(priority) {
+        // The interrupt entry saved the user registers on the task's own kernel stack,
+        // they will be restored when the task is switched back in
+        switch_to_next_task();
+    }
+    exit_if_requested();
+}
+
+/// Terminates the current thread if its process is exiting.
+/// Called before going back to userspace, no locks should be held
+pub fn exit_if_requested() {
+    if current_task().read().exit_requested {
+        exit_current_thread(ExitReason::Exited(0));
+    }
+}
+
+/// Terminates the current thread, it will never be resumed.
+/// If it's the last thread of its process the process exits too (with reason, unless the
+/// process has been terminated in other ways)
+pub fn exit_current_thread(reason: ExitReason) -> ! {
+    {
+        let task_lock = current_task();
+        let mut task = task_lock.write();
+        task.exit_reason = reason;
+        task.state = TaskState::Dying;
+    }
+    switch_to_next_task();
+    unreachable!("Exited task has been resumed");
+}
+
+/// Terminates the whole process of the current task, its other threads exit as soon as they notice
+pub fn exit_current_process(reason: ExitReason) -> ! {
+    {
+        let current = current_task_id();
+        let group = current_task().read().group.clone();
+        let others = {
+            let threads = group.threads.lock();
+            group.exit_reason.lock().get_or_insert(reason);
+            threads.iter()
+                .copied()
+                .filter(|x| *x != current)
+                .collect::<Vec<_>>()
+        };
+        for id in others {
+            request_exit(id);
+        }
+    }
+    exit_current_thread(reason)
+}
+
+fn request_exit(id: TaskId) {
+    let task_lock = match tasks().get(id) {
+        Some(x) => x.clone(),
+        None => return,
+    };
+    task_lock.write().exit_requested = true;
+    // A blocked thread will notice when its wait is interrupted, the others when they go back to userspace
+    wait_queue::wake_task(id);
+}
+
+fn idle_task_id() -> TaskId {
+    match NonZeroU64::new(IDLE_TASK_ID.load(Ordering::SeqCst)) {
+        Some(x) => TaskId(x),
+        None => panic!("Idle task not set yet"),
+    }
+}
+
+/// Makes the code running on this core its idle task, must be called once per core
+pub unsafe fn become_idle_task(ctx: TaskContext) {
+    cpu::register_cpu();
+    let id = ctx.id;
+    tasks_mut().add(ctx);
+    IDLE_TASK_ID.store(id.0.get(), Ordering::SeqCst);
+    set_current_task_id(id);
+}
+
+/// Runs the other tasks, sleeping when there's nothing to do
+pub fn idle_loop() -> ! {
+    loop {
+        // Timer interrupts that wake us up don't touch the timers (they can't know if it's safe)
+        time::process_timers();
+        if !switch_to_next_task() {
+            x86_64::instructions::interrupts::enable_and_hlt();
+        }
+    }
+}
+
 pub fn set_current_task_id(id: TaskId) {
     TASK_ID.store(id.0.get(), Ordering::SeqCst)
 }
@@ -96,12 +230,125 @@ extern "C" fn after_task_switch() {
         let [farc, tarc] = arcs.unwrap_unchecked();
 
         // Safety: well, right now the lock is "forgot" but we hold it
-        let farc2 = &mut *farc.as_mut_ptr();
-        if farc2.state == TaskState::Dying {
-            tasks_mut().remove(farc2.id);
-        }
+        let (from_id, from_state, from_affinity, from_priority) = {
+            let from = &mut *farc.as_mut_ptr();
+            from.on_cpu = false;
+            (from.id, from.state, from.affinity, from.priority)
+        };
 
         farc.force_write_unlock();
         tarc.force_write_unlock();
+
+        // Now that its context is saved it can run on any core
+        if from_state == TaskState::User {
+            scheduler::enqueue(from_id, from_affinity, from_priority);
+        }
+        if from_state == TaskState::Dying {
+            release_exited_task(farc);
+        }
+    };
+}
+
+/// Frees the resources of a thread that exited, the process is released with its last thread
+fn release_exited_task(task_lock: Arc<RwLock<TaskContext>>) {
+    let mut task = task_lock.write();
+    let id = task.id;
+    let process = task.process;
+    let group = task.group.clone();
+    // We're running on another task's stack, so it's safe to free it.
+    // The user stack is mapped in the page table shared with the other threads, it goes with the process
+    let kernel_stack = task.kernel_stack.take();
+    if task.is_process() {
+        task.state = TaskState::ThreadExited;
+    }
+    let is_last = {
+        let mut threads = group.threads.lock();
+        threads.retain(|x| *x != id);
+        if threads.is_empty() {
+            group.exit_reason.lock().get_or_insert(task.exit_reason);
+        }
+        threads.is_empty()
+    };
+    let is_process = task.is_process();
+    drop(task);
+    drop(kernel_stack);
+
+    // Other threads aren't reaped by anyone, the shared parts are dropped with them
+    let removed = if is_process { None } else { tasks_mut().remove(id) };
+    drop(removed);
+
+    if is_last {
+        let process_lock = tasks().get(process).cloned();
+        if let Some(x) = process_lock {
+            release_exited_process(x);
+        }
+    }
+}
+
+/// Closest ancestor of id that's an alive subreaper, or the orphan reaper.
+/// The ancestors are locked one at a time, never while holding the registry lock
+fn find_adopter(id: TaskId) -> Option<TaskId> {
+    let task_lock = |x| tasks().get(x).cloned();
+    let mut current = task_lock(id).and_then(|x| x.read().parent);
+    while let Some(ancestor_id) = current {
+        let ancestor_lock = match task_lock(ancestor_id) {
+            Some(x) => x,
+            None => break,
+        };
+        let ancestor = ancestor_lock.read();
+        if ancestor.subreaper && !ancestor.state.has_exited() {
+            return Some(ancestor_id);
+        }
+        current = ancestor.parent;
+    }
+
+    let reaper = tasks().orphan_reaper.filter(|x| *x != id)?;
+    let alive = !task_lock(reaper)?.read().state.has_exited();
+    alive.then(|| reaper)
+}
+
+/// Frees the resources of a process whose threads all exited, it's kept as a zombie until its parent reaps it
+fn release_exited_process(task_lock: Arc<RwLock<TaskContext>>) {
+    let mut task = task_lock.write();
+    task.state = TaskState::Zombie;
+    let group_reason = *task.group.exit_reason.lock();
+    if let Some(reason) = group_reason {
+        task.exit_reason = reason;
+    }
+    let id = task.id;
+    let parent = task.parent;
+    // Closing everything now lets the others notice (ex. the peer of a conversation),
+    // the other threads are gone so nobody else is using them
+    let capabilities = mem::take(&mut *task.capabilities.lock());
+    let files = mem::take(&mut task.files.lock().handles);
+    let fault_port = task.fault_port.take();
+    // No thread can use it anymore
+    let user_stack = task.user_stack.take();
+    drop(task);
+    drop((capabilities, files, fault_port, user_stack));
+
+    let (orphans, removed, to_notify) = loop {
+        let adopter = find_adopter(id);
+        let mut tasks = tasks_mut();
+        let orphans = match tasks.reparent_children(id, adopter) {
+            Some(x) => x,
+            None => continue,
+        };
+        // Nobody will reap it
+        let removed = if parent.map_or(true, |p| tasks.get(p).is_none()) {
+            tasks.remove(id)
+        } else {
+            None
+        };
+        // The parent can reap us and the adopter might have got some zombies
+        let to_notify = [parent, adopter].map(|x| {
+            x.and_then(|x| tasks.get(x)).map(|x| x.read().child_exit.clone())
+        });
+        break (orphans, removed, to_notify);
     };
+    // Woken and dropped outside of the registry lock
+    for queue in to_notify.iter().flatten() {
+        queue.wake_all();
+    }
+    drop((orphans, removed));
 }
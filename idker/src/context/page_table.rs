@@ -1,11 +1,54 @@
 use alloc::boxed::Box;
-use x86_64::{VirtAddr, structures::paging::{FrameDeallocator, OffsetPageTable, PageTable, PageTableFlags, PhysFrame, Translate, page_table::{PageTableEntry, PageTableLevel}}};
+use spin::Mutex;
+use x86_64::{VirtAddr, structures::paging::{FrameDeallocator, OffsetPageTable, PageSize, PageTable, PageTableFlags, PhysFrame, Size4KiB, Translate, page::PageRange, page_table::{PageTableEntry, PageTableLevel}}};
 
-use crate::{allocator::{HeapFrameAllocator, get_frame_allocator}, arch::paging::{get_page_table, physical_memory_offset}};
+use crate::{allocator::{HeapFrameAllocator, get_frame_allocator}, arch::{paging::{get_page_table, physical_memory_offset}, random, tlb}};
 
+use super::task::TaskDataPage;
+
+// Only one table at a time is unmapped while the other cpus flush, or an unmap could reclaim
+// what another one detached before it's flushed
+static UNMAPPING: Mutex<()> = Mutex::new(());
+
+// Every process gets its own random bases, each one is a page chosen in a 1 TiB area (28 bits)
+const ASLR_PAGE_BITS: u32 = 28;
+const PIE_LOAD_AREA: u64 = 0x1000_0000_0000;
+const MMAP_AREA: u64 = 0x2000_0000_0000;
+const INTERP_AREA: u64 = 0x3000_0000_0000;
+const STACK_AREA: u64 = 0x7000_0000_0000;
+
+/// Where the parts of an address space that can move go
+#[derive(Clone, Copy, Debug, Default)]
+pub struct AddressLayout {
+    // Position-independent programs are loaded here (the others where they are linked)
+    pub load_base: u64,
+    // The loader of dynamically linked programs (PT_INTERP) is loaded here
+    pub interp_base: u64,
+    // Bottom of the user stack of the first thread
+    pub stack_addr: u64,
+    // MemoryMapVirt picks the addresses of mappings from here
+    pub mmap_base: u64,
+}
+
+impl AddressLayout {
+    pub fn random() -> Self {
+        let page_in = |area: u64| area + random::below(1 << ASLR_PAGE_BITS) * Size4KiB::SIZE;
+        AddressLayout {
+            load_base: page_in(PIE_LOAD_AREA),
+            interp_base: page_in(INTERP_AREA),
+            stack_addr: page_in(STACK_AREA),
+            mmap_base: page_in(MMAP_AREA),
+        }
+    }
+}
 
 pub struct UserPageTable {
     page_table: Box<PageTable>,
+    pub layout: AddressLayout,
+    // Next address given to a MemoryMapVirt that lets the kernel choose
+    pub mmap_next: u64,
+    // Mapped at KERNEL_DATA_PAGE_ADDR, owned here so it can't be freed while a table still maps it
+    pub data_page: Option<Box<TaskDataPage>>,
 }
 
 impl UserPageTable {
@@ -16,6 +59,9 @@ impl UserPageTable {
         // but it that happens we're in big trouble, the initial page table isn't in heap memory
         UserPageTable {
             page_table: Box::from_raw(table),
+            layout: AddressLayout::default(),
+            mmap_next: 0,
+            data_page: None,
         }
     }
 
@@ -27,8 +73,12 @@ impl UserPageTable {
             new_table[index] = entry.clone();// Clone the POINTER, NOT THE WHOLE SUB-TABLE
         }
 
+        let layout = AddressLayout::random();
         UserPageTable {
             page_table: new_table,
+            layout,
+            mmap_next: layout.mmap_base,
+            data_page: None,
         }
     }
 
@@ -43,41 +93,160 @@ impl UserPageTable {
         let paddr = self.offset_page().translate_addr(vaddr).unwrap();
         PhysFrame::from_start_address(paddr).unwrap()
     }
-}
 
-impl Drop for UserPageTable {
-    fn drop(&mut self) {
-        unsafe fn unmap_worker(falloc: &mut HeapFrameAllocator, entry: &mut PageTableEntry, level: PageTableLevel) {
-            if !entry.flags().contains(PageTableFlags::PRESENT) ||
-                entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+    /// Unmaps the pages in range (the ones that aren't mapped are skipped) from a table that might
+    /// be loaded on other cpus, it's not locked while they flush (they could be waiting for it).
+    /// Frames allocated by the kernel for the pages (BIT_10) and the tables left empty are freed
+    /// once no cpu can use them anymore, other frames (ex. from MemoryMapPhys) are left alone
+    pub fn unmap_shared(table: &Mutex<UserPageTable>, range: PageRange) {
+        let _guard = UNMAPPING.lock();
+        let frame = {
+            let mut user_table = table.lock();
+            if !user_table.detach_range(range) {
                 return;
             }
+            user_table.get_frame()
+        };
+        // If some cpu doesn't answer the entries stay detached, they're freed with the table
+        if tlb::shootdown(frame.start_address().as_u64(), range.start.start_address(), range.count() as u64) {
+            table.lock().reclaim_range(range);
+        }
+    }
 
-            // First deallocate lower levels, then deallocate myself
-            // if we are a level 2 entry we're pointing to level 1 page tables so there is no need to recurr
-            // (we just unmap level 1 tables, but we don't unmap what they point to.)
-            if level != PageTableLevel::Two {
-                let lower_level = level.next_lower_level().unwrap();// We're not on level 1.
-                let offset = physical_memory_offset();
-                let table = &mut *(offset + entry.addr().as_u64()).as_mut_ptr() as &mut PageTable;
-                for e in table.iter_mut() {
-                    unmap_worker(falloc, e, lower_level);
-                }
-            }
+    /// Like unmap_shared but the table stays locked, only for tables that nobody runs yet (ex. a process being loaded)
+    pub fn unmap(&mut self, range: PageRange) {
+        let _guard = UNMAPPING.lock();
+        if !self.detach_range(range) {
+            return;
+        }
+        let table = self.get_frame().start_address().as_u64();
+        if tlb::shootdown(table, range.start.start_address(), range.count() as u64) {
+            self.reclaim_range(range);
+        }
+    }
 
-            // It must be present and not huge
-            let frame = entry.frame().unwrap();
-            falloc.deallocate_frame(frame);
+    /// Detaches the pages in range, returns false if it's empty
+    fn detach_range(&mut self, range: PageRange) -> bool {
+        let start = range.start.start_address().as_u64();
+        let end = range.end.start_address().as_u64();
+        if start >= end {
+            return false;
         }
+        // The entries keep their frame until every TLB has been flushed, then they're freed
+        unsafe { detach(&mut self.page_table, PageTableLevel::Four, 0, start, end) };
+        true
+    }
 
+    fn reclaim_range(&mut self, range: PageRange) {
+        let start = range.start.start_address().as_u64();
+        let end = range.end.start_address().as_u64();
         let mut falloc = get_frame_allocator();
+        unsafe { reclaim(&mut falloc, &mut self.page_table, PageTableLevel::Four, 0, start, end) };
+    }
+}
+
+// Unmapping happens in two steps: detach clears the PRESENT flag of the entries but keeps their
+// frames, tables that become empty are detached from their parent in the same way. After the
+// TLBs (and the paging-structure caches) are flushed reclaim frees everything that was detached.
+// Entries are never left non-present with a frame otherwise.
+
+fn entry_size(level: PageTableLevel) -> u64 {
+    match level {
+        PageTableLevel::One => Size4KiB::SIZE,
+        PageTableLevel::Two => Size4KiB::SIZE << 9,
+        PageTableLevel::Three => Size4KiB::SIZE << 18,
+        PageTableLevel::Four => Size4KiB::SIZE << 27,
+    }
+}
+
+// In level 1 entries the bit is PAT
+fn is_huge(level: PageTableLevel, flags: PageTableFlags) -> bool {
+    level != PageTableLevel::One && flags.contains(PageTableFlags::HUGE_PAGE)
+}
+
+unsafe fn table_of(entry: &PageTableEntry) -> &mut PageTable {
+    &mut *(physical_memory_offset() + entry.addr().as_u64()).as_mut_ptr()
+}
+
+/// Entries of table (that starts mapping at base) that map something in [start, end)
+fn entries_in(table: &mut PageTable, level: PageTableLevel, base: u64, start: u64, end: u64) -> impl Iterator<Item=(u64, &mut PageTableEntry)> {
+    let size = entry_size(level);
+    table.iter_mut()
+        .enumerate()
+        .map(move |(index, entry)| (base + index as u64 * size, entry))
+        .filter(move |(from, _)| *from < end && from + size > start)
+}
+
+/// Detaches the pages in [start, end), returns true if table has no present entry left
+unsafe fn detach(table: &mut PageTable, level: PageTableLevel, base: u64, start: u64, end: u64) -> bool {
+    for (from, entry) in entries_in(table, level, base, start, end) {
+        let flags = entry.flags();
+        // Userspace only has 4KiB pages
+        if !flags.contains(PageTableFlags::PRESENT) || is_huge(level, flags) {
+            continue;
+        }
+        let empty = match level.next_lower_level() {
+            Some(lower) => detach(table_of(entry), lower, from, start, end),
+            None => true,
+        };
+        if empty {
+            entry.set_flags(flags - PageTableFlags::PRESENT);
+        }
+    }
+    table.iter().all(|x| !x.flags().contains(PageTableFlags::PRESENT))
+}
+
+/// Frees what detach left in [start, end)
+unsafe fn reclaim(falloc: &mut HeapFrameAllocator, table: &mut PageTable, level: PageTableLevel, base: u64, start: u64, end: u64) {
+    for (from, entry) in entries_in(table, level, base, start, end) {
+        let flags = entry.flags();
+        if entry.is_unused() || is_huge(level, flags) {
+            continue;
+        }
+        if let Some(lower) = level.next_lower_level() {
+            // Present tables can have detached entries too
+            reclaim(falloc, table_of(entry), lower, from, start, end);
+        }
+        if flags.contains(PageTableFlags::PRESENT) {
+            continue;
+        }
+        // Leaf frames are only ours if the kernel allocated them, tables always are
+        if level != PageTableLevel::One || flags.contains(PageTableFlags::BIT_10) {
+            falloc.deallocate_frame(PhysFrame::containing_address(entry.addr()));
+        }
+        entry.set_unused();
+    }
+}
+
+impl Drop for UserPageTable {
+    fn drop(&mut self) {
         // Deallocate only the lower entries since the kernel entries are shared between all of the tables
         // (shared, not copied, they literally point to the same subtables)
-        for e in self.page_table.iter_mut().take(256) {
-            unsafe {
-                unmap_worker(&mut falloc, e, PageTableLevel::Four);
-            }
+        let end = entry_size(PageTableLevel::Four) * 256;
+        let mut falloc = get_frame_allocator();
+        // No cpu has the table loaded anymore, so there's nothing to flush between the two steps
+        unsafe {
+            detach(&mut self.page_table, PageTableLevel::Four, 0, 0, end);
+            reclaim(&mut falloc, &mut self.page_table, PageTableLevel::Four, 0, 0, end);
         }
         // the last table is the boxed one, and will be dropped after this
     }
 }
+
+#[test_case]
+fn address_layout_random_in_areas() {
+    let area_size = Size4KiB::SIZE << ASLR_PAGE_BITS;
+    for _ in 0..16 {
+        let layout = AddressLayout::random();
+        let bases = [
+            (layout.load_base, PIE_LOAD_AREA),
+            (layout.interp_base, INTERP_AREA),
+            (layout.stack_addr, STACK_AREA),
+            (layout.mmap_base, MMAP_AREA),
+        ];
+        for (base, area) in bases {
+            assert!(base >= area && base < area + area_size);
+            assert_eq!(base % Size4KiB::SIZE, 0);
+        }
+    }
+}
@@ -1,18 +1,21 @@
-use alloc::{collections::{BTreeMap, VecDeque}, sync::Arc};
+use core::mem;
+
+use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
 use spin::RwLock;
 
-use super::{TaskContext, TaskId};
+use super::{TaskContext, TaskId, task::TaskState};
 
 pub struct TaskRegistry {
     tasks: BTreeMap<TaskId, Arc<RwLock<TaskContext>>>,
-    pub executable_tasks: VecDeque<TaskId>,
+    // Adopts the orphans that have no subreaper ancestor (the initproc task)
+    pub orphan_reaper: Option<TaskId>,
 }
 
 impl TaskRegistry {
     pub fn new() -> Self {
         TaskRegistry {
             tasks: BTreeMap::new(),
-            executable_tasks: VecDeque::new(),
+            orphan_reaper: None,
         }
     }
 
@@ -48,7 +51,45 @@ impl TaskRegistry {
         task
     }
 
-    pub fn queue_for_execution(&mut self, id: TaskId) {
-        self.executable_tasks.push_back(id);
+    /// Gives the children of an exited task to adopter (its closest subreaper ancestor or the orphan reaper),
+    /// the adopter can then wait for them as if they were its own.
+    /// Returns the removed zombies if nobody could adopt them, or None if the adopter has exited
+    /// in the meantime (nothing is changed, another adopter should be found)
+    pub fn reparent_children(&mut self, id: TaskId, adopter: Option<TaskId>) -> Option<Vec<Arc<RwLock<TaskContext>>>> {
+        if let Some(x) = adopter {
+            if self.tasks.get(&x).map_or(true, |t| t.read().state.has_exited()) {
+                return None;
+            }
+        }
+        let children = match self.tasks.get(&id) {
+            Some(x) => mem::take(&mut x.write().children),
+            None => return Some(Vec::new()),
+        };
+        if children.is_empty() {
+            return Some(Vec::new());
+        }
+
+        for child in children.iter() {
+            if let Some(x) = self.tasks.get(child) {
+                x.write().set_parent(adopter);
+            }
+        }
+
+        match adopter.and_then(|x| self.tasks.get(&x)) {
+            Some(adopter) => {
+                adopter.write().children.extend(children);
+                Some(Vec::new())
+            }
+            None => {
+                // Zombies without a parent would never be reaped
+                let zombies: Vec<TaskId> = children.into_iter()
+                    .filter(|x| self.tasks.get(x).map_or(false, |t| t.read().state == TaskState::Zombie))
+                    .collect();
+                let removed = zombies.into_iter()
+                    .filter_map(|x| self.remove(x))
+                    .collect();
+                Some(removed)
+            }
+        }
     }
 }
\ No newline at end of file
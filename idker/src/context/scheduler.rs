@@ -0,0 +1,159 @@
+use alloc::collections::VecDeque;
+use spin::Mutex;
+use syscall::Priority;
+
+use crate::arch::cpu::{self, CpuMask, MAX_CPUS};
+
+use super::TaskId;
+
+// Every core has its own run queue so that switching tasks doesn't need any global lock.
+// Tasks are queued on the least loaded core they can run on, and a core with nothing to do
+// steals work from the others.
+// Each run queue is split by priority class: real-time tasks always run first (higher levels
+// first, round-robin within the same level), then normal tasks, then idle ones.
+
+struct QueuedTask {
+    id: TaskId,
+    // Copy of the task affinity and priority, so that stealing doesn't need to lock the tasks
+    affinity: CpuMask,
+    priority: Priority,
+}
+
+struct RunQueue {
+    // Sorted by decreasing level
+    realtime: VecDeque<QueuedTask>,
+    normal: VecDeque<QueuedTask>,
+    idle: VecDeque<QueuedTask>,
+}
+
+impl RunQueue {
+    const fn new() -> Self {
+        RunQueue {
+            realtime: VecDeque::new(),
+            normal: VecDeque::new(),
+            idle: VecDeque::new(),
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.realtime.len() + self.normal.len() + self.idle.len()
+    }
+
+    fn push(&mut self, task: QueuedTask) {
+        match task.priority {
+            Priority::RealTime(_) => {
+                // After the tasks with the same level
+                let index = self.realtime.iter()
+                    .position(|x| x.priority < task.priority)
+                    .unwrap_or(self.realtime.len());
+                self.realtime.insert(index, task);
+            }
+            Priority::Normal => self.normal.push_back(task),
+            Priority::Idle => self.idle.push_back(task),
+        }
+    }
+
+    /// Takes a task out of the queue, returns false if it wasn't there
+    fn remove(&mut self, id: TaskId) -> bool {
+        for class in self.classes_mut() {
+            if let Some(index) = class.iter().position(|x| x.id == id) {
+                class.remove(index);
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Queues from the most to the least important
+    fn classes_mut(&mut self) -> [&mut VecDeque<QueuedTask>; 3] {
+        [&mut self.realtime, &mut self.normal, &mut self.idle]
+    }
+
+    /// Priority of the most important task
+    fn best_priority(&self) -> Option<Priority> {
+        [&self.realtime, &self.normal, &self.idle].iter()
+            .find_map(|x| x.front())
+            .map(|x| x.priority)
+    }
+}
+
+const EMPTY_QUEUE: Mutex<RunQueue> = Mutex::new(RunQueue::new());
+static RUN_QUEUES: [Mutex<RunQueue>; MAX_CPUS] = [EMPTY_QUEUE; MAX_CPUS];
+
+/// Queues a task that can be executed, it must not be running
+pub fn enqueue(id: TaskId, affinity: CpuMask, priority: Priority) {
+    let allowed = affinity & cpu::online_mask();
+    let current = cpu::cpu_id();
+
+    // Prefer the current core (its caches are warm), then the least loaded one
+    let target = if allowed & (1 << current) != 0 {
+        current
+    } else {
+        (0..cpu::cpu_count())
+            .filter(|x| allowed & (1 << x) != 0)
+            .min_by_key(|x| RUN_QUEUES[*x].lock().len())
+            // Affinities are checked when they're set, but a task must never be lost
+            .unwrap_or(current)
+    };
+
+    RUN_QUEUES[target].lock().push(QueuedTask { id, affinity, priority });
+}
+
+/// Updates the affinity and priority of a queued task, moving it to a core where it can run.
+/// A task that isn't queued will use them the next time it is
+pub fn requeue(id: TaskId, affinity: CpuMask, priority: Priority) {
+    let found = (0..cpu::cpu_count()).any(|x| RUN_QUEUES[x].lock().remove(id));
+    if found {
+        enqueue(id, affinity, priority);
+    }
+}
+
+/// Takes the next task to run on the current core (stealing it from another core if needed).
+/// If at_least is set, only tasks that are at least as important are taken
+pub fn dequeue(at_least: Option<Priority>) -> Option<TaskId> {
+    let current = cpu::cpu_id();
+    {
+        let mut queue = RUN_QUEUES[current].lock();
+        if queue.best_priority().map_or(false, |x| at_least.map_or(true, |min| x >= min)) {
+            for class in queue.classes_mut() {
+                if let Some(x) = class.pop_front() {
+                    return Some(x.id);
+                }
+            }
+        }
+    }
+    steal(current, at_least)
+}
+
+/// True if a task more important than priority is waiting on the current core
+pub fn has_more_important(priority: Priority) -> bool {
+    RUN_QUEUES[cpu::cpu_id()].lock().best_priority()
+        .map_or(false, |x| x > priority)
+}
+
+fn steal(current: usize, at_least: Option<Priority>) -> Option<TaskId> {
+    let count = cpu::cpu_count();
+    let can_take = |x: &QueuedTask| {
+        x.affinity & (1 << current) != 0 && at_least.map_or(true, |min| x.priority >= min)
+    };
+    // The most important class of any core first
+    for class in 0..3 {
+        // Start from the next core so that the victims are spread out
+        for victim in (1..count).map(|x| (current + x) % count) {
+            let mut queue = RUN_QUEUES[victim].lock();
+            let classes = queue.classes_mut();
+            let tasks = &mut *classes[class];
+            let index = if class == 0 {
+                // Real-time tasks are sorted, take the most important one
+                tasks.iter().position(can_take)
+            } else {
+                // The back of the queue is the task that would wait the longest there
+                tasks.iter().rposition(can_take)
+            };
+            if let Some(index) = index {
+                return tasks.remove(index).map(|x| x.id);
+            }
+        }
+    }
+    None
+}
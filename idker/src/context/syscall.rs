@@ -1,71 +1,613 @@
-use core::num::NonZeroU64;
+use core::{convert::TryFrom, mem::{align_of, size_of}, num::NonZeroU64, slice};
 
-use x86_64::VirtAddr;
+use alloc::{sync::Arc, vec::Vec};
+use spin::RwLock;
+use x86_64::{VirtAddr, registers::model_specific::KernelGsBase};
 
-use crate::{capability::CapabilityType, file::read_file_to_memory, println, syscalls::{enter_userspace}};
-use syscall::{SyscallError, SyscallResult};
+use crate::{arch::cpu::{self, CpuMask}, capability::{CapabilityPerms, CapabilityType, syscall::CapabilityHandle}, file::{FileHandle, ProcRootFs, read_file_to_memory, syscall::open_for_read}, println, syscalls::{TCD, check_addr_userspace, enter_userspace, user_virt_addr}};
+use syscall::{CapabilityPassMode, EXEC_MAX_ARGS, EXEC_MAX_ARGS_SIZE, ExecArg, ExitReason, SPAWN_MAX_CAPABILITIES, SPAWN_MAX_FILES, SpawnCapability, SpawnExecArgs, Priority, ProcessWaitMode, SyscallError, SyscallResult, TlsRegister};
 
-use super::{TaskContext, TaskId, current_task, current_task_id, elf::Elf, tasks, tasks_mut};
+use super::{TaskContext, TaskId, current_process, current_process_id, current_task, current_task_id, elf::Elf, exit_current_process, exit_current_thread, exit_if_requested, scheduler, switch_to_next_task, task::TaskState, tasks, tasks_mut};
 
 
+// Programs bigger than this can't be executed
+const EXEC_MAX_FILE_SIZE: usize = 1024 * 1024 * 1024;// 1 GiB
+// How many #! scripts can run each other before exec gives up
+const EXEC_MAX_SCRIPT_DEPTH: usize = 4;
+// The #! line of a script must fit in this many bytes
+const SHEBANG_MAX_LENGTH: usize = 256;
+
 pub fn mypid() -> SyscallResult<TaskId> {
+    Ok(current_process_id())
+}
+
+pub fn mytid() -> SyscallResult<TaskId> {
     Ok(current_task_id())
 }
 
+pub fn exit(code: usize) -> ! {
+    exit_current_process(ExitReason::Exited(code as u64))
+}
+
+pub fn thread_exit(code: usize) -> ! {
+    exit_current_thread(ExitReason::Exited(code as u64))
+}
+
+pub fn wait(pid: usize, mode: usize) -> SyscallResult<(TaskId, ExitReason)> {
+    let pid = NonZeroU64::new(pid as u64).map(TaskId);
+    let mode = ProcessWaitMode::from_bits_truncate(mode as u8);
+
+    let zombie = loop {
+        match find_zombie_child(pid)? {
+            Some(x) => break x,
+            None if mode.contains(ProcessWaitMode::NON_BLOCKING) => return Err(SyscallError::ProcessRunning),
+            None => {
+                let child_exit = current_process().read().child_exit.clone();
+                child_exit.wait_while(|| matches!(find_zombie_child(pid), Ok(None)))?;
+            }
+        }
+    };
+
+    let child_lock = tasks_mut().remove(zombie).ok_or(SyscallError::WrongProcess)?;
+    let exit_reason = child_lock.read().exit_reason;
+    Ok((zombie, exit_reason))
+}
+
+/// Finds a child that exited (any child if pid is None)
+fn find_zombie_child(pid: Option<TaskId>) -> SyscallResult<Option<TaskId>> {
+    let process = current_process_id();
+    let tasks = tasks();
+    let task = tasks.get(process)
+            .expect("Current process not in registry")
+            .read();
+
+    match pid {
+        Some(p) if !task.children.contains(&p) => return Err(SyscallError::WrongProcess),
+        None if task.children.is_empty() => return Err(SyscallError::WrongProcess),
+        _ => {},
+    }
+
+    let zombie = task.children.iter()
+            .copied()
+            .filter(|x| pid.map_or(true, |p| p == *x))
+            .find(|x| {
+                // A locked child is running (or being switched), so it's not a zombie
+                tasks.get(*x)
+                    .and_then(|t| t.try_read())
+                    .map_or(false, |t| t.state == TaskState::Zombie)
+            });
+    Ok(zombie)
+}
+
+pub fn set_subreaper(enabled: usize) -> SyscallResult<()> {
+    let task_lock = current_process();
+    let mut task = task_lock.write();
+    task.subreaper = enabled != 0;
+    Ok(())
+}
+
+/// Finds the task targeted by a process syscall: the current thread (pid 0) or a child process
+pub fn self_or_child(pid: usize) -> SyscallResult<Arc<RwLock<TaskContext>>> {
+    let pid = match NonZeroU64::new(pid as u64) {
+        Some(x) => TaskId(x),
+        None => return Ok(current_task()),
+    };
+    let task_lock = tasks().get(pid)
+            .ok_or(SyscallError::WrongProcess)?
+            .clone();
+    if task_lock.read().parent != Some(current_process_id()) {
+        return Err(SyscallError::WrongProcess);
+    }
+    Ok(task_lock)
+}
+
+pub fn set_affinity(pid: usize, mask: usize) -> SyscallResult<()> {
+    let mask = mask as CpuMask;
+    if mask & cpu::online_mask() == 0 {
+        return Err(SyscallError::WrongParameters);
+    }
+    let target_lock = self_or_child(pid)?;
+    let (id, priority) = {
+        let mut target = target_lock.write();
+        target.affinity = mask;
+        (target.id, target.priority)
+    };
+
+    if pid == 0 {
+        // The current task must be moved right away
+        if mask & (1 << cpu::cpu_id()) == 0 {
+            switch_to_next_task();
+        }
+    } else {
+        // The child might be waiting on a core it can't use anymore
+        scheduler::requeue(id, mask, priority);
+    }
+    Ok(())
+}
+
+pub fn set_priority(pid: usize, class: usize, level: usize) -> SyscallResult<()> {
+    let priority = Priority::from_raw(class as u64, level as u64).ok_or(SyscallError::WrongParameters)?;
+    let can_raise = {
+        let task_lock = current_task();
+        let task = task_lock.read();
+        let caps = task.capabilities.lock();
+        caps.handles.iter()
+            .any(|x| x.1.ctype == CapabilityType::SchedulerPriority)
+    };
+
+    // The priority belongs to the process, every thread gets it
+    let group = self_or_child(pid)?.read().group.clone();
+    let ids = group.threads.lock().clone();
+    let threads = {
+        let tasks = tasks();
+        ids.iter()
+            .filter_map(|x| tasks.get(*x).cloned())
+            .collect::<Vec<_>>()
+    };
+    if !can_raise && threads.iter().any(|x| priority > x.read().priority) {
+        return Err(SyscallError::WrongCapability);
+    }
+    for thread_lock in threads {
+        let (id, affinity) = {
+            let mut thread = thread_lock.write();
+            thread.priority = priority;
+            (thread.id, thread.affinity)
+        };
+        // Queued threads must wait with their new class
+        scheduler::requeue(id, affinity, priority);
+    }
+
+    if pid == 0 {
+        // Someone else might be more important now
+        switch_to_next_task();
+    }
+    Ok(())
+}
+
 pub fn spawn() -> SyscallResult<TaskId> {
-    let task_lock = current_task();
-    let task = task_lock.write();
-    // Check capability
-    task.capabilities.handles.iter()
-            .find(|x| x.1.ctype == CapabilityType::ProcessSpawn)
-            .ok_or(SyscallError::WrongCapability)?;
+    let (process, priority) = {
+        let task_lock = current_task();
+        let task = task_lock.read();
+        // Check capability
+        task.capabilities.lock().handles.iter()
+                .find(|x| x.1.ctype == CapabilityType::ProcessSpawn)
+                .ok_or(SyscallError::WrongCapability)?;
+        (task.process, task.priority)
+    };
 
-    let child = TaskContext::create(current_task_id(), jmp_userspace);
+    let mut child = TaskContext::create(process, jmp_userspace);
+    // Children are as important as their parent
+    child.priority = priority;
     let child_id = child.id;
+    // Adding the child locks the parent
     tasks_mut().add(child);
 
     Ok(child_id)
 }
 
-pub fn exec(proc_id: usize, fd: usize) -> SyscallResult<()> {
+/// Starts a new thread in the current process, it will run entry(arg) with the stack pointer set to stack
+pub fn spawn_thread(entry: usize, stack: usize, arg: usize) -> SyscallResult<TaskId> {
+    // Entering userspace at a non-canonical address would fault in the kernel
+    let entry = user_virt_addr(entry)?;
+    check_addr_userspace(stack)?;
+    let mut thread = {
+        let task_lock = current_task();
+        let task = task_lock.read();
+        TaskContext::create_thread(&task, jmp_userspace)
+    };
+    thread.user_entry_point = entry;
+    thread.user_entry_arg = arg as u64;
+    thread.tcd.user_stack_pointer = stack as u64;
+    let (id, affinity, priority, group) = (thread.id, thread.affinity, thread.priority, thread.group.clone());
+
+    // The thread must be in the registry before joining its group, or an exiting process couldn't stop it
+    tasks_mut().add(thread);
+    let joined = {
+        let mut threads = group.threads.lock();
+        if group.exit_reason.lock().is_some() {
+            Err(SyscallError::Interrupted)
+        } else {
+            threads.try_reserve(1).map(|_| threads.push(id)).map_err(SyscallError::from)
+        }
+    };
+    if let Err(e) = joined {
+        let removed = tasks_mut().remove(id);
+        drop(removed);
+        return Err(e);
+    }
+
+    scheduler::enqueue(id, affinity, priority);
+    Ok(id)
+}
+
+/// Sets the FS or GS base of the current thread, they are loaded when it goes back to userspace
+pub fn set_tls_base(register: usize, address: usize) -> SyscallResult<()> {
+    let register = TlsRegister::try_from(register as u64).map_err(|_| SyscallError::WrongParameters)?;
+    // Loading a non-canonical address would fault in the kernel
+    let address = VirtAddr::try_new(address as u64).map_err(|_| SyscallError::WrongParameters)?;
+    check_addr_userspace(address.as_u64() as usize)?;
+    unsafe {
+        match register {
+            TlsRegister::Fs => TCD.user_fs_base = address.as_u64(),
+            TlsRegister::Gs => {
+                TCD.user_gs_base = address.as_u64();
+                // The user GS base stays there until swapgs
+                KernelGsBase::write(address);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Reads a string passed as an ExecArg from userspace
+fn read_exec_string(arg: &ExecArg) -> SyscallResult<&'static [u8]> {
+    let (ptr, len) = (arg.ptr as usize, arg.len as usize);
+    if len > EXEC_MAX_ARGS_SIZE {
+        return Err(SyscallError::ArgumentsTooLong);
+    }
+    if len == 0 {
+        return Ok(&[]);
+    }
+    check_addr_userspace(ptr)?;
+    check_addr_userspace(ptr + len)?;
+    Ok(unsafe { slice::from_raw_parts(ptr as *const u8, len) })
+}
+
+/// Reads an array of ExecArg strings from userspace
+fn read_exec_args(ptr: usize, count: usize) -> SyscallResult<Vec<&'static [u8]>> {
+    if count > EXEC_MAX_ARGS {
+        return Err(SyscallError::ArgumentsTooLong);
+    }
+    if count == 0 {
+        return Ok(Vec::new());
+    }
+    if ptr % align_of::<ExecArg>() != 0 {
+        return Err(SyscallError::WrongParameters);
+    }
+    check_addr_userspace(ptr)?;
+    check_addr_userspace(ptr + count * size_of::<ExecArg>())?;
+    let args = unsafe { slice::from_raw_parts(ptr as *const ExecArg, count) };
+
+    let mut strings = Vec::new();
+    strings.try_reserve(count)?;
+    for arg in args {
+        strings.push(read_exec_string(arg)?);
+    }
+    Ok(strings)
+}
+
+fn to_owned_bytes(x: &[u8]) -> SyscallResult<Vec<u8>> {
+    let mut owned = Vec::new();
+    owned.try_reserve_exact(x.len())?;
+    owned.extend_from_slice(x);
+    Ok(owned)
+}
+
+/// Parses the #! line of a script, returns the interpreter and its optional argument
+fn parse_shebang(data: &[u8]) -> SyscallResult<Option<(&[u8], Option<&[u8]>)>> {
+    if !data.starts_with(b"#!") {
+        return Ok(None);
+    }
+    let line = &data[2..data.len().min(SHEBANG_MAX_LENGTH)];
+    let line = match line.iter().position(|x| *x == b'\n') {
+        Some(end) => &line[..end],
+        None if data.len() <= SHEBANG_MAX_LENGTH => line,
+        // The interpreter might be cut
+        None => return Err(SyscallError::FsNotExecutable),
+    };
+    let is_blank = |x: &u8| *x == b' ' || *x == b'\t';
+    let start = line.iter().position(|x| !is_blank(x)).ok_or(SyscallError::FsNotExecutable)?;
+    let line = &line[start..];
+    let (interpreter, rest) = line.split_at(line.iter().position(is_blank).unwrap_or(line.len()));
+    // Like Linux everything after the interpreter is a single argument
+    let argument = match (rest.iter().position(|x| !is_blank(x)), rest.iter().rposition(|x| !is_blank(x))) {
+        (Some(from), Some(to)) => Some(&rest[from..=to]),
+        _ => None,
+    };
+    Ok(Some((interpreter, argument)))
+}
+
+fn read_path(root: &Arc<RwLock<ProcRootFs>>, path: &[u8]) -> SyscallResult<Vec<u8>> {
+    let path = core::str::from_utf8(path)?;
+    let mut handle = open_for_read(root, path)?;
+    read_file_to_memory(handle.as_mut(), EXEC_MAX_FILE_SIZE)
+}
+
+/// A program ready to be loaded
+struct ExecImage {
+    program: Vec<u8>,
+    // The loader asked by the program with PT_INTERP
+    interpreter: Option<Vec<u8>>,
+    argv: Vec<Vec<u8>>,
+}
+
+/// Finds what actually runs when program (called name) is executed: a #! script runs its interpreter,
+/// argv becomes the interpreter, its optional argument, the script name and the rest of argv.
+/// The loader asked by an ELF program is read too, paths are read with open
+fn read_program(open: &mut dyn FnMut(&[u8]) -> SyscallResult<Vec<u8>>, mut program: Vec<u8>, name: &[u8], argv: &[&[u8]]) -> SyscallResult<ExecImage> {
+    let mut name = to_owned_bytes(name)?;
+    let mut owned_argv = Vec::new();
+    owned_argv.try_reserve_exact(argv.len())?;
+    for x in argv {
+        owned_argv.push(to_owned_bytes(x)?);
+    }
+    let mut argv = owned_argv;
+
+    let mut depth = 0;
+    while let Some((interpreter, argument)) = parse_shebang(&program)? {
+        depth += 1;
+        if depth > EXEC_MAX_SCRIPT_DEPTH {
+            return Err(SyscallError::ExecInterpreterLoop);
+        }
+        let interpreter = to_owned_bytes(interpreter)?;
+        let mut script_argv = Vec::new();
+        script_argv.try_reserve_exact(argv.len() + 2)?;
+        script_argv.push(to_owned_bytes(&interpreter)?);
+        if let Some(x) = argument {
+            script_argv.push(to_owned_bytes(x)?);
+        }
+        script_argv.push(name);
+        // The old argv[0] is replaced by the script name
+        script_argv.extend(argv.drain(..).skip(1));
+
+        program = open(&interpreter)?;
+        name = interpreter;
+        argv = script_argv;
+    }
+
+    let interpreter = match Elf::new(&program, 0)?.interpreter()? {
+        Some(path) => Some(open(path.as_bytes())?),
+        None => None,
+    };
+    Ok(ExecImage { program, interpreter, argv })
+}
+
+/// Like read_program for a program read from handle, a script is named by the path the handle
+/// was opened with (its interpreter couldn't find it otherwise)
+fn read_handle_program(handle: &mut dyn FileHandle, open: &mut dyn FnMut(&[u8]) -> SyscallResult<Vec<u8>>, argv: &[&[u8]]) -> SyscallResult<ExecImage> {
+    let program = read_file_to_memory(handle, EXEC_MAX_FILE_SIZE)?;
+    let name = match handle.path() {
+        Some(path) => path.as_bytes(),
+        None if parse_shebang(&program)?.is_some() => return Err(SyscallError::FsNotExecutable),
+        None => argv.first().copied().unwrap_or(&[]),
+    };
+    read_program(open, program, name, argv)
+}
+
+/// Loads a program in the empty process proc, with its argv and envp on the initial stack
+fn load_program(proc: &mut TaskContext, image: &ExecImage, envp: &[&[u8]]) -> SyscallResult<()> {
+    let layout = proc.page_table.lock().layout;
+    let elf = Elf::new(&image.program, layout.load_base)?;
+    let interpreter = match &image.interpreter {
+        Some(x) => Some(Elf::new(x, layout.interp_base)?),
+        None => None,
+    };
+    // The loader can't need a loader
+    if let Some(x) = &interpreter {
+        if x.interpreter()?.is_some() {
+            return Err(SyscallError::FsNotExecutable);
+        }
+    }
+
+    let mut argv = Vec::new();
+    argv.try_reserve_exact(image.argv.len())?;
+    argv.extend(image.argv.iter().map(|x| x.as_slice()));
+    proc.prepare_initial_stack(&elf, interpreter.as_ref(), &argv, envp)?;
+    proc.load_elf(&elf, interpreter.as_ref())
+}
+
+pub fn exec(proc_id: usize, fd: usize, argv: usize, argc: usize, envp: usize, envc: usize) -> SyscallResult<()> {
     let proc_id = TaskId(NonZeroU64::new(proc_id as u64).ok_or(SyscallError::WrongParameters)?);
+    let argv = read_exec_args(argv, argc)?;
+    let envp = read_exec_args(envp, envc)?;
     let proc_guard = tasks().get(proc_id)
             .ok_or(SyscallError::WrongProcess)?
             .clone();
     let mut proc = proc_guard.write();
-    if proc.parent != Some(current_task_id()) {
+    if proc.parent != Some(current_process_id()) {
         return Err(SyscallError::WrongProcess);
     }
     if proc.user_entry_point != VirtAddr::zero() {
         return Err(SyscallError::WrongProcess);
     }
     let curr_guard = current_task();
-    let mut curr = curr_guard.write();
+    let curr = curr_guard.read();
 
-    let file_handle =  &mut curr.files.handles.iter_mut()
+    let mut files = curr.files.lock();
+    let root = files.root.clone();
+    let file_handle =  &mut files.handles.iter_mut()
             .find(|x| x.0.get() == fd)
             .ok_or(SyscallError::WrongDescriptor)?.1;
 
-    // Limit: 1 GiB
-    let file = read_file_to_memory(file_handle.as_mut(), 1024 * 1024 * 1024)?;
-    let elf = Elf::new(&file).map_err(|_| SyscallError::FsNotExecutable)?;
-    proc.load_elf(&elf);
-    tasks_mut().queue_for_execution(proc.id);
-    // then start program
+    let image = read_handle_program(file_handle.as_mut(), &mut |path| read_path(&root, path), &argv)?;
+    load_program(&mut proc, &image, &envp)?;
+    scheduler::enqueue(proc.id, proc.affinity, proc.priority);
 
     Ok(())
 }
 
+/// Reads a userspace array of count T, T must be valid for every bit pattern
+fn read_user_array<T: Copy>(ptr: usize, count: usize) -> SyscallResult<Vec<T>> {
+    let mut values = Vec::new();
+    if count == 0 {
+        return Ok(values);
+    }
+    check_addr_userspace(ptr)?;
+    check_addr_userspace(ptr + count * size_of::<T>())?;
+    values.try_reserve_exact(count)?;
+    for i in 0..count {
+        // Userspace gives no alignment guarantees
+        values.push(unsafe { (ptr as *const T).add(i).read_unaligned() });
+    }
+    Ok(values)
+}
+
+/// Spawns a child that runs the program at path with the capabilities and the files listed in args.
+/// Everything is checked and the child is fully set up before the parent is touched: either the
+/// child starts with all of it or nothing happens
+pub fn spawn_exec(args_ptr: usize) -> SyscallResult<TaskId> {
+    let args = read_user_array::<SpawnExecArgs>(args_ptr, 1)?[0];
+    let path = core::str::from_utf8(read_exec_string(&args.path)?)?;
+    let argv = read_exec_args(args.argv as usize, args.argc as usize)?;
+    let envp = read_exec_args(args.envp as usize, args.envc as usize)?;
+    if args.capabilities_len as usize > SPAWN_MAX_CAPABILITIES || args.files_len as usize > SPAWN_MAX_FILES {
+        return Err(SyscallError::WrongParameters);
+    }
+    let passed_caps = read_user_array::<SpawnCapability>(args.capabilities as usize, args.capabilities_len as usize)?;
+    let fds = read_user_array::<u64>(args.files as usize, args.files_len as usize)?;
+
+    let (process, priority, task_caps, task_files) = {
+        let task_lock = current_task();
+        let task = task_lock.read();
+        (task.process, task.priority, task.capabilities.clone(), task.files.clone())
+    };
+    task_caps.lock().handles.iter()
+            .find(|x| x.1.ctype == CapabilityType::ProcessSpawn)
+            .ok_or(SyscallError::WrongCapability)?;
+
+    // The program is read and the child is created and loaded without holding any lock of the parent,
+    // only what's passed is copied
+    let (root, inherited) = {
+        let files = task_files.lock();
+        let mut inherited = Vec::new();
+        inherited.try_reserve_exact(fds.len())?;
+        for (i, &fd) in fds.iter().enumerate() {
+            if fds[..i].contains(&fd) {
+                return Err(SyscallError::WrongParameters);
+            }
+            let (descriptor, handle) = files.handles.iter()
+                    .find(|x| x.0.get() as u64 == fd)
+                    .ok_or(SyscallError::WrongDescriptor)?;
+            inherited.push((*descriptor, handle.duplicate()));
+        }
+        (files.root.clone(), inherited)
+    };
+    let program = read_path(&root, path.as_bytes())?;
+    let image = read_program(&mut |path| read_path(&root, path), program, path.as_bytes(), &argv)?;
+
+    let modes = passed_caps.iter()
+            .map(|x| CapabilityPassMode::try_from(x.mode).map_err(|_| SyscallError::WrongParameters))
+            .collect::<SyscallResult<Vec<_>>>()?;
+    // A capability can only be transferred once
+    for (i, passed) in passed_caps.iter().enumerate() {
+        if modes[i] == CapabilityPassMode::Transfer && passed_caps[..i].iter().any(|x| x.handle == passed.handle) {
+            return Err(SyscallError::WrongParameters);
+        }
+    }
+    let needed = |mode| match mode {
+        CapabilityPassMode::Share => CapabilityPerms::SHAREABLE,
+        CapabilityPassMode::Transfer => CapabilityPerms::TRANSFER,
+    };
+    // Check every capability before touching anything, like talk_capabilities
+    let capabilities = {
+        let caps = task_caps.lock();
+        let mut capabilities = Vec::new();
+        capabilities.try_reserve_exact(passed_caps.len())?;
+        for (passed, &mode) in passed_caps.iter().zip(modes.iter()) {
+            let cap = caps.get(passed.handle as CapabilityHandle).ok_or(SyscallError::WrongCapability)?;
+            if !cap.perms.contains(needed(mode)) {
+                return Err(SyscallError::WrongCapabilityPerms);
+            }
+            capabilities.push(cap.clone());
+        }
+        capabilities
+    };
+
+    // The child isn't in the registry yet, if anything fails it's simply dropped
+    let mut child = TaskContext::create(process, jmp_userspace);
+    // Children are as important as their parent
+    child.priority = priority;
+    load_program(&mut child, &image, &envp)?;
+    {
+        let mut child_caps = child.capabilities.lock();
+        for cap in capabilities {
+            child_caps.insert(cap)?;
+        }
+    }
+    child.files.lock().inherit(inherited);
+
+    // Another thread might have used the transferred capabilities meanwhile (handles are never reused)
+    let mut caps = task_caps.lock();
+    let mut transferred = Vec::new();
+    transferred.try_reserve_exact(passed_caps.len())?;
+    let to_transfer = passed_caps.iter()
+            .zip(modes.iter())
+            .filter(|(_, &mode)| mode == CapabilityPassMode::Transfer)
+            .map(|(x, _)| x.handle as CapabilityHandle);
+    let still_held = to_transfer.clone()
+            .all(|x| caps.get(x).map_or(false, |cap| cap.perms.contains(CapabilityPerms::TRANSFER)));
+    if !still_held {
+        return Err(SyscallError::WrongCapability);
+    }
+    // Nothing can fail from here on
+    for handle in to_transfer {
+        transferred.push(caps.delete(handle)?);
+    }
+    drop(caps);
+
+    let (child_id, affinity) = (child.id, child.affinity);
+    // Adding the child locks the parent
+    tasks_mut().add(child);
+    scheduler::enqueue(child_id, affinity, priority);
+    // Dropped without locks, like in cdrop
+    drop(transferred);
+    Ok(child_id)
+}
+
 extern fn jmp_userspace() {
-    let entry_point = {
+    // The process might have exited before the thread could start
+    exit_if_requested();
+    let (entry_point, arg) = {
         let ctxp = current_task();
         let ctx = ctxp.read();
-        ctx.user_entry_point
+        (ctx.user_entry_point, ctx.user_entry_arg)
     };
     //print_tables();
     if entry_point == VirtAddr::zero() {
         println!("Tried to jump into an uninitialized task");
     } else {
-        unsafe { enter_userspace(entry_point) };
+        unsafe { enter_userspace(entry_point, arg) };
     }
-}
\ No newline at end of file
+}
+#[test_case]
+fn shebang_parsed() {
+    let script = |interpreter: &'static [u8], argument: Option<&'static [u8]>| Ok(Some((interpreter, argument)));
+    assert_eq!(parse_shebang(b"\x7fELF#!/bin/sh\n"), Ok(None));
+    assert_eq!(parse_shebang(b"#!/bin/sh\necho hi\n"), script(b"/bin/sh", None));
+    assert_eq!(parse_shebang(b"#!/bin/sh"), script(b"/bin/sh", None));
+    // Everything after the interpreter is one argument, without the blanks around it
+    assert_eq!(parse_shebang(b"#! \t/bin/env  python -u \t\nprint()"), script(b"/bin/env", Some(b"python -u")));
+    assert_eq!(parse_shebang(b"#!  \n"), Err(SyscallError::FsNotExecutable));
+
+    let mut long = [b'a'; SHEBANG_MAX_LENGTH + 1];
+    long[..3].copy_from_slice(b"#!/");
+    assert_eq!(parse_shebang(&long), Err(SyscallError::FsNotExecutable));
+    long[SHEBANG_MAX_LENGTH - 1] = b'\n';
+    assert_eq!(parse_shebang(&long).map(|x| x.map(|(path, _)| path.len())), Ok(Some(SHEBANG_MAX_LENGTH - 3)));
+}
+
+#[test_case]
+fn exec_script_from_descriptor() {
+    use alloc::boxed::Box;
+    use crate::file::{InitFsFileHandle, syscall::NamedFileHandle};
+    use super::elf::{test_program, test_program_bytes, test_segment};
+
+    let sh = test_program(test_segment(0x40_0000, 0, 0x1000, goblin::elf64::program_header::PF_R));
+    let sh = test_program_bytes(&sh);
+    let mut open = |path: &[u8]| match path {
+        b"/bin/sh" => to_owned_bytes(sh),
+        _ => Err(SyscallError::InvalidPath),
+    };
+    let script = b"#!/bin/sh -e\necho hi\n";
+
+    let mut handle = NamedFileHandle::new("/init/hello", Box::new(InitFsFileHandle::new(script))).unwrap();
+    let image = read_handle_program(&mut handle, &mut open, &[b"hello", b"world"]).unwrap();
+    assert!(image.program == sh);
+    assert!(image.interpreter.is_none());
+    assert!(image.argv == [b"/bin/sh".as_slice(), b"-e", b"/init/hello", b"world"]);
+
+    // Its interpreter couldn't open it
+    let mut handle = InitFsFileHandle::new(script);
+    let image = read_handle_program(&mut handle, &mut open, &[b"hello"]);
+    assert_eq!(image.err(), Some(SyscallError::FsNotExecutable));
+}
@@ -1,18 +1,23 @@
 use core::{num::NonZeroU64, sync::atomic::{AtomicU64, Ordering}};
 
-use alloc::{boxed::Box, vec::Vec};
-use x86_64::{VirtAddr, structures::paging::{Mapper, Page, PageTableFlags, Size4KiB}};
+use alloc::{boxed::Box, sync::Arc, vec, vec::Vec};
+use spin::Mutex;
+use syscall::{AT_BASE, AT_ENTRY, AT_NULL, AT_PAGESZ, AT_PHDR, AT_PHENT, AT_PHNUM, AT_RANDOM, EXEC_MAX_ARGS, EXEC_MAX_ARGS_SIZE, ExitReason, KERNEL_DATA_PAGE_ADDR, KernelDataPage, Priority, SyscallError, SyscallResult};
+use x86_64::{VirtAddr, structures::paging::{Mapper, Page, PageSize, PageTableFlags, Size4KiB}};
 
-use crate::{allocator::get_frame_allocator, arch::paging::get_page_table, capability::syscall::TaskCapabilityStorage, file::syscall::TaskFileStorage, syscalls::TCD};
+use crate::{allocator::get_frame_allocator, arch::{cpu::{self, CpuMask}, fpu::FpuState, random, tsc}, arch::paging::get_page_table, capability::syscall::TaskCapabilityStorage, conversation::ConversationEndpoint, file::syscall::TaskFileStorage, syscalls::{TCD, ThreadControlData}, time};
 
-use super::{UserPageTable, elf::Elf, switch::ContextRegs};
+use super::{UserPageTable, elf::Elf, switch::ContextRegs, wait_queue::WaitQueue};
 
 #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, Hash)]
 pub struct TaskId(pub NonZeroU64);
 
 const KERNEL_STACK_SIZE: usize = 16 * 1024;// 64Kb
 const USER_STACK_SIZE: usize = 64 * 1024;// 64Kb
-const USERSPACE_STACK_ADDR: u64 = 0x4000_0000;
+// Random bytes at the top of the initial stack, pointed by AT_RANDOM
+const RANDOM_BYTES: usize = 16;
+// How many scheduler ticks a task can run in userspace before being preempted (50ms)
+pub const TIME_SLICE_TICKS: u32 = 5;
 static NEXT_PID: AtomicU64 = AtomicU64::new(2);
 
 pub struct OwnedStack<const SIZE: usize>(pub Box<[u8; SIZE]>);
@@ -26,7 +31,26 @@ impl<const SIZE: usize> OwnedStack<SIZE> {
     }
 }
 
+// The kernel data page of a task, it's shared read-only with userspace so it must fill a page
+#[repr(C, align(4096))]
+pub struct TaskDataPage(KernelDataPage);
 
+impl TaskDataPage {
+    fn new(id: TaskId, parent: Option<TaskId>) -> Box<Self> {
+        Box::new(TaskDataPage(KernelDataPage {
+            pid: id.0.get(),
+            parent_pid: parent.map_or(0, |x| x.0.get()),
+            boot_time_ns: time::boot_time_ns(),
+            tsc_ticks_per_ms: tsc::ticks_per_ms(),
+            cpu_count: cpu::started_cpu_count() as u64,
+        }))
+    }
+
+    fn set_parent(&mut self, parent: Option<TaskId>) {
+        // Userspace might be reading it right now
+        unsafe { core::ptr::write_volatile(&mut self.0.parent_pid, parent.map_or(0, |x| x.0.get())) };
+    }
+}
 
 fn allocate_pid() -> TaskId {
     let raw = NEXT_PID.fetch_add(1, Ordering::SeqCst);
@@ -43,43 +67,122 @@ fn allocate_pid() -> TaskId {
 pub enum TaskState {
     Sleepy,// Sleepy tasks are initial tasks, they do nothing but sleep (like me!)
     User,
-    Dying,// Will be killed after switching
+    Blocked,// Sleeping in a WaitQueue
+    Dying,// Exited, its resources will be released after switching
+    ThreadExited,// The first thread of a process exited, the process lives while its other threads run
+    Zombie,// Exited and released, waiting for its parent to reap it
+}
+
+impl TaskState {
+    pub fn has_exited(&self) -> bool {
+        matches!(self, TaskState::Dying | TaskState::Zombie)
+    }
+}
+
+/// Threads of a process, shared between them
+pub struct ThreadGroup {
+    // Threads that haven't been released yet
+    pub threads: Mutex<Vec<TaskId>>,
+    // Set by the first thread that terminates the whole process (always lock threads first)
+    pub exit_reason: Mutex<Option<ExitReason>>,
+}
+
+impl ThreadGroup {
+    fn new(first: TaskId) -> Arc<Self> {
+        Arc::new(ThreadGroup {
+            threads: Mutex::new(vec![first]),
+            exit_reason: Mutex::new(None),
+        })
+    }
 }
 
+// A task is a thread, a process is made of the threads that share a page table, the capabilities
+// and the files. The first thread also represents the process: the process id is its id and only
+// its process fields (parent, children, fault port...) are used.
 pub struct TaskContext {
     pub id: TaskId,
+    // Id of the first thread of the process
+    pub process: TaskId,
+    pub group: Arc<ThreadGroup>,
+    // Set when the process is exiting, the thread will exit as soon as it notices
+    pub exit_requested: bool,
     pub parent: Option<TaskId>,
     pub children: Vec<TaskId>,
+    // Adopts the orphaned descendants instead of the orphan reaper
+    pub subreaper: bool,
     pub state: TaskState,
+    // Only meaningful once the task exited
+    pub exit_reason: ExitReason,
+    // True from when the task is switched in until its context is saved after switching out
+    pub on_cpu: bool,
+    // Woken up when a child exits (or an orphan is adopted)
+    pub child_exit: Arc<WaitQueue>,
+    // Cores where the task can run
+    pub affinity: CpuMask,
+    // Scheduling class, see the scheduler
+    pub priority: Priority,
+    // Timer ticks left before the task is preempted (refilled when it's switched in)
+    pub time_slice: u32,
     pub arch_regs: ContextRegs,
-    pub capabilities: TaskCapabilityStorage,
-    pub files: TaskFileStorage,
-    pub page_table: UserPageTable,
+    // User x87/SSE/AVX registers, swapped on task switch
+    pub fpu: FpuState,
+    // The syscall stack pointers of the task, swapped with TCD on task switch
+    // (a task switched out during a syscall needs them back when it returns to userspace)
+    pub tcd: ThreadControlData,
+    pub capabilities: Arc<Mutex<TaskCapabilityStorage>>,
+    pub files: Arc<Mutex<TaskFileStorage>>,
+    // Where faults are reported (instead of killing the task), see the fault module
+    pub fault_port: Option<ConversationEndpoint>,
+    pub page_table: Arc<Mutex<UserPageTable>>,
     pub kernel_stack: Option<OwnedStack<KERNEL_STACK_SIZE>>,
     pub user_stack: Option<OwnedStack<USER_STACK_SIZE>>,
     // Runtime data, it should be inside of kernel_stack
     // When switched the kernel stack pointer is saved here
     pub user_entry_point: VirtAddr,
+    // Passed in rdi when entering userspace
+    pub user_entry_arg: u64,
 }
 
 impl TaskContext {
     pub unsafe fn create_init() -> Self {
-        let id = TaskId(NonZeroU64::new(1).unwrap());
+        Self::new_idle(TaskId(NonZeroU64::new(1).unwrap()))
+    }
+
+    /// Idle task of an application processor (the bootstrap one uses the init task)
+    pub unsafe fn create_idle() -> Self {
+        Self::new_idle(allocate_pid())
+    }
+
+    unsafe fn new_idle(id: TaskId) -> Self {
         let mut ctx = TaskContext {
             id,
+            process: id,
+            group: ThreadGroup::new(id),
+            exit_requested: false,
             parent: None,
             children: Vec::new(),
+            subreaper: false,
             state: TaskState::Sleepy,
+            exit_reason: ExitReason::Exited(0),
+            on_cpu: true,// Idle tasks are created by the code they run
+            child_exit: Arc::new(WaitQueue::new()),
+            affinity: CpuMask::MAX,
+            priority: Priority::Normal,
+            time_slice: TIME_SLICE_TICKS,
             arch_regs: ContextRegs::default(),
-            page_table: UserPageTable::from_current(),
+            fpu: FpuState::new(),
+            tcd: ThreadControlData::new(),
+            page_table: Arc::new(Mutex::new(UserPageTable::from_current())),
             kernel_stack: None,
             user_stack: None,
             user_entry_point: VirtAddr::zero(),
+            user_entry_arg: 0,
             capabilities: Default::default(),
-            files: TaskFileStorage::new(id),
+            files: Arc::new(Mutex::new(TaskFileStorage::new(id))),
+            fault_port: None,
         };
 
-        ctx.arch_regs.reload_cr3(&mut ctx.page_table);
+        ctx.arch_regs.reload_cr3(&mut ctx.page_table.lock());
 
         ctx
     }
@@ -89,33 +192,108 @@ impl TaskContext {
         let id = allocate_pid();
         let mut ctx = TaskContext {
             id,
+            process: id,
+            group: ThreadGroup::new(id),
+            exit_requested: false,
             parent: Some(parent),
             children: Vec::new(),
+            subreaper: false,
             state: TaskState::User,
+            exit_reason: ExitReason::Exited(0),
+            on_cpu: false,
+            child_exit: Arc::new(WaitQueue::new()),
+            affinity: CpuMask::MAX,
+            priority: Priority::Normal,
+            time_slice: TIME_SLICE_TICKS,
             arch_regs: ContextRegs::default(),
-            page_table: UserPageTable::new_from(ktable.level_4_table()),
+            fpu: FpuState::new(),
+            tcd: ThreadControlData::new(),
+            page_table: Arc::new(Mutex::new(UserPageTable::new_from(ktable.level_4_table()))),
             kernel_stack: Some(OwnedStack::alloc_uninit()),
             user_stack: Some(OwnedStack::alloc_uninit()),
             user_entry_point: VirtAddr::zero(),
+            user_entry_arg: 0,
             capabilities: Default::default(),
-            files: TaskFileStorage::new(id),
+            files: Arc::new(Mutex::new(TaskFileStorage::new(id))),
+            fault_port: None,
         };
 
-        ctx.arch_regs.reload_cr3(&mut ctx.page_table);
+        ctx.page_table.lock().data_page = Some(TaskDataPage::new(id, Some(parent)));
+        ctx.arch_regs.reload_cr3(&mut ctx.page_table.lock());
+        ctx.prepare_kernel_stack(func);
 
-        let kernel_stack = &ctx.kernel_stack.as_ref().unwrap().0;
+        unsafe {
+            ctx.mount_user_stack();
+            ctx.mount_data_page();
+        }
+
+        ctx
+    }
+
+    /// Creates another thread of the process of task, it will start by running func.
+    /// The user stack is provided by userspace
+    pub fn create_thread(task: &TaskContext, func: extern fn()) -> Self {
+        let id = allocate_pid();
+        let mut ctx = TaskContext {
+            id,
+            process: task.process,
+            group: task.group.clone(),
+            exit_requested: false,
+            parent: None,// Only the first thread has process relationships
+            children: Vec::new(),
+            subreaper: false,
+            state: TaskState::User,
+            exit_reason: ExitReason::Exited(0),
+            on_cpu: false,
+            child_exit: Arc::new(WaitQueue::new()),
+            affinity: task.affinity,
+            priority: task.priority,
+            time_slice: TIME_SLICE_TICKS,
+            arch_regs: ContextRegs::default(),
+            fpu: FpuState::new(),
+            tcd: ThreadControlData::new(),
+            page_table: task.page_table.clone(),
+            kernel_stack: Some(OwnedStack::alloc_uninit()),
+            user_stack: None,
+            user_entry_point: VirtAddr::zero(),
+            user_entry_arg: 0,
+            capabilities: task.capabilities.clone(),
+            files: task.files.clone(),
+            fault_port: None,
+        };
+
+        ctx.arch_regs.reload_cr3(&mut ctx.page_table.lock());
+        ctx.prepare_kernel_stack(func);
+
+        ctx
+    }
+
+    pub fn is_process(&self) -> bool {
+        self.id == self.process
+    }
+
+    fn prepare_kernel_stack(&mut self, func: extern fn()) {
         // The stack grows back
-        ctx.arch_regs.rsp = kernel_stack.as_ptr() as usize + kernel_stack.len();
+        self.arch_regs.rsp = self.kernel_stack_end().unwrap().as_u64() as usize;
         // When we switch to this context we will call the code using a "ret" instruction
         // the ret will take an address from the stack and jump to that, so at the top of the stack
         // there should be the initial function
         unsafe {
-            ctx.arch_regs.push_stack(func as usize);
+            self.arch_regs.push_stack(func as usize);
         }
+    }
 
-        unsafe { ctx.mount_user_stack() };
+    pub fn set_parent(&mut self, parent: Option<TaskId>) {
+        self.parent = parent;
+        if let Some(page) = self.page_table.lock().data_page.as_mut() {
+            page.set_parent(parent);
+        }
+    }
 
-        ctx
+    /// Top of the kernel stack, interrupts from userspace start from here
+    pub fn kernel_stack_end(&self) -> Option<VirtAddr> {
+        self.kernel_stack.as_ref()
+            .map(|x| VirtAddr::from_ptr(x.0.as_ptr()) + x.0.len())
     }
 
     unsafe fn mount_user_stack(&mut self) {
@@ -129,8 +307,9 @@ impl TaskContext {
             Page::containing_address(stack_start + user_stack.len() - 1u64),
         );
 
-        let mut offset_page = self.page_table.offset_page();
-        let mut user_page = Page::containing_address(VirtAddr::new(USERSPACE_STACK_ADDR));
+        let mut user_table = self.page_table.lock();
+        let mut user_page = Page::containing_address(VirtAddr::new(user_table.layout.stack_addr));
+        let mut offset_page = user_table.offset_page();
         for heap_page in heap_stack_range {
             let real_stack = page_table
                 .translate_page(heap_page)
@@ -152,12 +331,98 @@ impl TaskContext {
         }
     }
 
+    unsafe fn mount_data_page(&mut self) {
+        let page_table = get_page_table();
+        let mut frame_allocator = get_frame_allocator();
+
+        let mut user_table = self.page_table.lock();
+        let data_page = user_table.data_page.as_ref().unwrap();
+        let heap_page = Page::<Size4KiB>::containing_address(VirtAddr::from_ptr(&**data_page as *const TaskDataPage));
+        let frame = page_table.translate_page(heap_page).unwrap();
+
+        user_table.offset_page()
+            .map_to(
+                Page::containing_address(VirtAddr::new(KERNEL_DATA_PAGE_ADDR)),
+                frame,
+                PageTableFlags::PRESENT
+                    | PageTableFlags::NO_EXECUTE
+                    | PageTableFlags::USER_ACCESSIBLE,
+                &mut *frame_allocator,
+            )
+            .unwrap()
+            .flush();
+    }
+
+    /// Loads the user stack pointer and FS base of the task, it must be the current one
     pub unsafe fn prepare_tcb(&self) {
-        TCD.user_stack_pointer = USERSPACE_STACK_ADDR + USER_STACK_SIZE as u64 - 128;
+        TCD.user_stack_pointer = self.tcd.user_stack_pointer;
+        TCD.user_fs_base = self.tcd.user_fs_base;
     }
 
-    pub fn load_elf(&mut self, elf: &Elf) {
-        elf.mount_into(&mut self.page_table.offset_page());
-        self.user_entry_point = VirtAddr::new(elf.header().e_entry);
+    /// Writes the initial stack of a new program as the SysV ABI describes it:
+    /// argc, argv, envp and the auxiliary vector, with the strings (and the AT_RANDOM bytes) above them
+    /// AT_ENTRY is the entry point of the program even if an interpreter runs first (at AT_BASE)
+    pub fn prepare_initial_stack(&mut self, elf: &Elf, interpreter: Option<&Elf>, argv: &[&[u8]], envp: &[&[u8]]) -> SyscallResult<()> {
+        if argv.len() > EXEC_MAX_ARGS || envp.len() > EXEC_MAX_ARGS {
+            return Err(SyscallError::ArgumentsTooLong);
+        }
+        let header = elf.header();
+        let mut auxv = Vec::new();
+        auxv.try_reserve(8)?;
+        if let Some(phdr) = elf.program_headers_address() {
+            auxv.extend_from_slice(&[(AT_PHDR, phdr), (AT_PHENT, header.e_phentsize as u64), (AT_PHNUM, header.e_phnum as u64)]);
+        }
+        auxv.extend_from_slice(&[(AT_PAGESZ, Size4KiB::SIZE), (AT_ENTRY, elf.entry_point())]);
+        if let Some(x) = interpreter {
+            auxv.push((AT_BASE, x.load_bias()));
+        }
+
+        // argc, the two null-terminated arrays, the AT_RANDOM entry and AT_NULL
+        let word_count = 1 + argv.len() + 1 + envp.len() + 1 + (auxv.len() + 2) * 2;
+        let strings_size: usize = argv.iter().chain(envp).map(|x| x.len() + 1).sum();
+        if RANDOM_BYTES + strings_size + word_count * 8 + 15 > EXEC_MAX_ARGS_SIZE {
+            return Err(SyscallError::ArgumentsTooLong);
+        }
+        let mut words = Vec::new();
+        words.try_reserve(word_count)?;
+
+        let stack_addr = self.page_table.lock().layout.stack_addr;
+        let stack = &mut self.user_stack.as_mut().ok_or(SyscallError::WrongProcess)?.0;
+        let mut top = stack.len() - RANDOM_BYTES;
+        random::fill_bytes(&mut stack[top..]);
+        auxv.push((AT_RANDOM, stack_addr + top as u64));
+
+        words.push(argv.len() as u64);
+        for strings in [argv, envp] {
+            for x in strings {
+                top -= x.len() + 1;
+                stack[top..][..x.len()].copy_from_slice(x);
+                stack[top + x.len()] = 0;
+                words.push(stack_addr + top as u64);
+            }
+            words.push(0);
+        }
+        for (key, value) in auxv.iter().chain(&[(AT_NULL, 0)]) {
+            words.extend_from_slice(&[*key, *value]);
+        }
+
+        // The stack pointer must be 16-byte aligned at the entry point, it points to argc
+        top = (top - words.len() * 8) & !15;
+        for (i, x) in words.iter().enumerate() {
+            stack[top + i * 8..][..8].copy_from_slice(&x.to_le_bytes());
+        }
+        self.tcd.user_stack_pointer = stack_addr + top as u64;
+        Ok(())
+    }
+
+    /// Maps the program (and its interpreter, that will run first) in the address space of the task,
+    /// nothing is left mapped if it fails
+    pub fn load_elf(&mut self, elf: &Elf, interpreter: Option<&Elf>) -> SyscallResult<()> {
+        let mut user_table = self.page_table.lock();
+        if let Some(thread_pointer) = elf.load(interpreter, &mut user_table)? {
+            self.tcd.user_fs_base = thread_pointer.as_u64();
+        }
+        self.user_entry_point = VirtAddr::new(interpreter.unwrap_or(elf).entry_point());
+        Ok(())
     }
 }
\ No newline at end of file
@@ -0,0 +1,92 @@
+use alloc::collections::VecDeque;
+use spin::Mutex;
+use syscall::{SyscallError, SyscallResult};
+
+use super::{TaskId, current_task, current_task_id, scheduler, switch_to_next_task, task::TaskState, tasks};
+
+// A list of tasks sleeping until something happens (a message arrives, a child exits...).
+// The condition is always checked with the queue locked, and wakers take the lock after changing
+// what the condition depends on, so no wake up can be lost between the check and the sleep.
+
+pub struct WaitQueue {
+    waiters: Mutex<VecDeque<TaskId>>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        WaitQueue {
+            waiters: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Blocks the current task while cond returns true.
+    /// No locks should be held by the caller, other tasks will run in the meantime.
+    /// Fails with Interrupted if the process is exiting, the caller should give up and return
+    pub fn wait_while(&self, mut cond: impl FnMut() -> bool) -> SyscallResult<()> {
+        let id = current_task_id();
+        loop {
+            {
+                let mut waiters = self.waiters.lock();
+                // We might have been woken up by someone else (ex. a timeout)
+                waiters.retain(|x| *x != id);
+                if !cond() {
+                    return Ok(());
+                }
+                let task_lock = current_task();
+                let mut task = task_lock.write();
+                // Checked with the task locked, so that the exit request can't be lost
+                if task.exit_requested {
+                    return Err(SyscallError::Interrupted);
+                }
+                if waiters.try_reserve(1).is_err() {
+                    // Cannot sleep without memory, fall back to polling
+                    drop((task, waiters));
+                    switch_to_next_task();
+                    continue;
+                }
+                waiters.push_back(id);
+                task.state = TaskState::Blocked;
+            }
+            // If we've been woken up in the meantime we are runnable again and will be rescheduled
+            switch_to_next_task();
+        }
+    }
+
+    /// Wakes the first waiting task, returns false if nobody was woken up
+    pub fn wake_one(&self) -> bool {
+        loop {
+            let next = self.waiters.lock().pop_front();
+            match next {
+                Some(x) if wake_task(x) => return true,
+                Some(_) => continue,
+                None => return false,
+            }
+        }
+    }
+
+    /// Wakes every waiting task, returns how many were woken up
+    pub fn wake_all(&self) -> usize {
+        let waiters = core::mem::take(&mut *self.waiters.lock());
+        waiters.into_iter()
+            .filter(|x| wake_task(*x))
+            .count()
+    }
+}
+
+/// Makes a blocked task runnable again, returns false if it wasn't blocked
+pub fn wake_task(id: TaskId) -> bool {
+    let task_lock = match tasks().get(id) {
+        Some(x) => x.clone(),
+        None => return false,
+    };
+    let mut task = task_lock.write();
+    if task.state != TaskState::Blocked {
+        return false;
+    }
+    task.state = TaskState::User;
+    // A task still on its cpu (it hasn't switched out yet) will notice it's runnable by itself
+    if !task.on_cpu {
+        scheduler::enqueue(task.id, task.affinity, task.priority);
+    }
+    true
+}
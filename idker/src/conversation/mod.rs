@@ -0,0 +1,203 @@
+use core::{fmt, sync::atomic::{AtomicBool, AtomicUsize, Ordering}};
+
+use alloc::{collections::VecDeque, sync::Arc, vec::Vec};
+use spin::Mutex;
+use ::syscall::{SyscallError, SyscallResult, CONVERSATION_MAX_QUEUED};
+
+use crate::{capability::Capability, context::{TaskId, wait_queue::WaitQueue}};
+
+pub mod syscall;
+
+// A conversation is the kernel object behind IPC: a bunch of message queues and the endpoints
+// used to talk into them. Endpoints are capabilities, so they are owned by tasks and they can be
+// shared or transferred like any other capability.
+// - P2p conversations have exactly two sides, what one side says is heard by the other one.
+// - Public conversations have a single queue, anyone holding an endpoint can talk or listen
+//   (useful for services, clients can then ask for a private p2p conversation).
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConversationKind {
+    P2p,
+    Public,
+}
+
+pub struct Message {
+    pub sender: TaskId,
+    pub data: Vec<u8>,
+    // Capabilities carried by the message, they are given to whoever hears it
+    pub capabilities: Vec<Capability>,
+}
+
+pub struct Conversation {
+    kind: ConversationKind,
+    // queues[i] contains the messages that side i will hear (public conversations only use queues[0])
+    queues: [Mutex<VecDeque<Message>>; 2],
+    // Tasks waiting for a message in the matching queue
+    listeners: [WaitQueue; 2],
+    // How many endpoints are alive on each side
+    endpoints: [AtomicUsize; 2],
+    // Messages with capabilities can't be put in the matching queue (the kernel listens there)
+    refuses_capabilities: [AtomicBool; 2],
+}
+
+impl Conversation {
+    fn new(kind: ConversationKind) -> Arc<Conversation> {
+        Arc::new(Conversation {
+            kind,
+            queues: [Mutex::new(VecDeque::new()), Mutex::new(VecDeque::new())],
+            listeners: [WaitQueue::new(), WaitQueue::new()],
+            endpoints: [AtomicUsize::new(0), AtomicUsize::new(0)],
+            refuses_capabilities: [AtomicBool::new(false), AtomicBool::new(false)],
+        })
+    }
+
+    pub fn create_p2p() -> (ConversationEndpoint, ConversationEndpoint) {
+        let conversation = Conversation::new(ConversationKind::P2p);
+        (
+            ConversationEndpoint::new(conversation.clone(), 0),
+            ConversationEndpoint::new(conversation, 1),
+        )
+    }
+
+    pub fn create_public() -> ConversationEndpoint {
+        ConversationEndpoint::new(Conversation::new(ConversationKind::Public), 0)
+    }
+}
+
+pub struct ConversationEndpoint {
+    conversation: Arc<Conversation>,
+    side: usize,
+}
+
+impl ConversationEndpoint {
+    fn new(conversation: Arc<Conversation>, side: usize) -> Self {
+        conversation.endpoints[side].fetch_add(1, Ordering::SeqCst);
+        ConversationEndpoint {
+            conversation,
+            side,
+        }
+    }
+
+    pub fn kind(&self) -> ConversationKind {
+        self.conversation.kind
+    }
+
+    // Index of the queue where the messages we hear are stored
+    fn inbox_index(&self) -> usize {
+        match self.kind() {
+            ConversationKind::P2p => self.side,
+            ConversationKind::Public => 0,
+        }
+    }
+
+    // Index of the queue where the messages we say are stored
+    fn outbox_index(&self) -> usize {
+        match self.kind() {
+            ConversationKind::P2p => 1 - self.side,
+            ConversationKind::Public => 0,
+        }
+    }
+
+    fn inbox(&self) -> &Mutex<VecDeque<Message>> {
+        &self.conversation.queues[self.inbox_index()]
+    }
+
+    fn outbox(&self) -> &Mutex<VecDeque<Message>> {
+        &self.conversation.queues[self.outbox_index()]
+    }
+
+    pub fn is_peer_closed(&self) -> bool {
+        match self.kind() {
+            ConversationKind::P2p => self.conversation.endpoints[1 - self.side].load(Ordering::SeqCst) == 0,
+            ConversationKind::Public => false,
+        }
+    }
+
+    /// True if other is the other side of the same p2p conversation
+    pub fn is_peer_of(&self, other: &ConversationEndpoint) -> bool {
+        self.kind() == ConversationKind::P2p &&
+            Arc::ptr_eq(&self.conversation, &other.conversation) && self.side != other.side
+    }
+
+    /// From now on the messages we would hear can't carry capabilities, who sends them gets an error
+    pub fn refuse_capabilities(&self) {
+        self.conversation.refuses_capabilities[self.inbox_index()].store(true, Ordering::SeqCst);
+    }
+
+    pub fn send(&self, message: Message) -> SyscallResult<()> {
+        if self.is_peer_closed() {
+            return Err(SyscallError::ConversationClosed);
+        }
+        if !message.capabilities.is_empty() && self.conversation.refuses_capabilities[self.outbox_index()].load(Ordering::SeqCst) {
+            return Err(SyscallError::ConversationCapabilitiesRefused);
+        }
+        let mut queue = self.outbox().lock();
+        if queue.len() >= CONVERSATION_MAX_QUEUED {
+            return Err(SyscallError::ConversationFull);
+        }
+        queue.try_reserve(1)?;
+        queue.push_back(message);
+        drop(queue);
+        self.conversation.listeners[self.outbox_index()].wake_one();
+        Ok(())
+    }
+
+    /// Blocks the current task until there's something to receive (or the peer closes)
+    pub fn wait_message(&self) -> SyscallResult<()> {
+        self.conversation.listeners[self.inbox_index()]
+            .wait_while(|| self.inbox().lock().is_empty() && !self.is_peer_closed())
+    }
+
+    /// Takes the next message (if it's not longer than max_len and it has at most max_caps capabilities)
+    pub fn receive(&self, max_len: usize, max_caps: usize) -> SyscallResult<Message> {
+        let mut queue = self.inbox().lock();
+        match queue.front() {
+            Some(x) if x.data.len() > max_len || x.capabilities.len() > max_caps => {
+                Err(SyscallError::ConversationBufferTooSmall)
+            },
+            Some(_) => Ok(queue.pop_front().unwrap()),
+            None if self.is_peer_closed() => Err(SyscallError::ConversationClosed),
+            None => Err(SyscallError::ConversationEmpty),
+        }
+    }
+
+    /// Puts back a message taken with receive, so that it will be the next one heard
+    pub fn give_back(&self, message: Message) {
+        // The slot freed by receive is still there, so this doesn't allocate
+        self.inbox().lock().push_front(message);
+    }
+}
+
+impl Clone for ConversationEndpoint {
+    fn clone(&self) -> Self {
+        ConversationEndpoint::new(self.conversation.clone(), self.side)
+    }
+}
+
+impl Drop for ConversationEndpoint {
+    fn drop(&mut self) {
+        let alive = self.conversation.endpoints[self.side].fetch_sub(1, Ordering::SeqCst) - 1;
+        if alive == 0 && self.kind() == ConversationKind::P2p {
+            // Whoever is listening on the other side will hear that we're gone
+            self.conversation.listeners[1 - self.side].wake_all();
+        }
+    }
+}
+
+impl PartialEq for ConversationEndpoint {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.conversation, &other.conversation) && self.side == other.side
+    }
+}
+
+impl Eq for ConversationEndpoint {}
+
+impl fmt::Debug for ConversationEndpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ConversationEndpoint")
+            .field("conversation", &Arc::as_ptr(&self.conversation))
+            .field("kind", &self.kind())
+            .field("side", &self.side)
+            .finish()
+    }
+}
@@ -0,0 +1,185 @@
+use core::mem::size_of;
+
+use alloc::vec::Vec;
+use syscall::{ConversationListenMode, SyscallError, SyscallResult, CONVERSATION_MAX_CAPABILITIES, CONVERSATION_MAX_MESSAGE_SIZE};
+
+use crate::{capability::{Capability, CapabilityPerms, CapabilityType, syscall::CapabilityHandle}, context::{current_process_id, current_task}, syscalls::check_addr_userspace};
+
+use super::{Conversation, ConversationEndpoint, ConversationKind, Message};
+
+
+fn check_can_create() -> SyscallResult<()> {
+    let task_lock = current_task();
+    let task = task_lock.read();
+    task.capabilities.lock().handles.iter()
+            .find(|x| x.1.ctype == CapabilityType::ChannelCreate)
+            .ok_or(SyscallError::WrongCapability)?;
+    Ok(())
+}
+
+fn get_endpoint(handle: CapabilityHandle) -> SyscallResult<ConversationEndpoint> {
+    let task_lock = current_task();
+    let task = task_lock.read();
+    let caps = task.capabilities.lock();
+    match &caps.get(handle).ok_or(SyscallError::WrongCapability)?.ctype {
+        CapabilityType::Conversation(x) => Ok(x.clone()),
+        _ => Err(SyscallError::WrongCapability),
+    }
+}
+
+pub fn create_p2p() -> SyscallResult<(CapabilityHandle, CapabilityHandle)> {
+    check_can_create()?;
+    let (a, b) = Conversation::create_p2p();
+
+    // Each side of a p2p conversation must have a single owner, so it can only be transferred
+    let perms = CapabilityPerms::TRANSFER;
+    let task_lock = current_task();
+    let task = task_lock.read();
+    let mut caps = task.capabilities.lock();
+    let ha = caps.insert(Capability { perms, ctype: CapabilityType::Conversation(a) })?;
+    let hb = match caps.insert(Capability { perms, ctype: CapabilityType::Conversation(b) }) {
+        Ok(x) => x,
+        Err(e) => {
+            caps.delete(ha)?;
+            return Err(e);
+        }
+    };
+    Ok((ha, hb))
+}
+
+pub fn create_public() -> SyscallResult<CapabilityHandle> {
+    check_can_create()?;
+    let endpoint = Conversation::create_public();
+
+    let perms = CapabilityPerms::DUPLICATE | CapabilityPerms::SHAREABLE | CapabilityPerms::TRANSFER;
+    let task_lock = current_task();
+    let task = task_lock.read();
+    let mut caps = task.capabilities.lock();
+    caps.insert(Capability { perms, ctype: CapabilityType::Conversation(endpoint) })
+}
+
+fn read_message_data(ptr: usize, len: usize) -> SyscallResult<Vec<u8>> {
+    if len > CONVERSATION_MAX_MESSAGE_SIZE {
+        return Err(SyscallError::WrongParameters);
+    }
+    check_addr_userspace(ptr)?;
+    check_addr_userspace(ptr + len)?;
+    let data = unsafe { core::slice::from_raw_parts(ptr as *const u8, len) };
+
+    let mut buffer = Vec::new();
+    buffer.try_reserve_exact(len)?;
+    buffer.extend_from_slice(data);
+    Ok(buffer)
+}
+
+fn read_capability_handles(ptr: usize, len: usize) -> SyscallResult<Vec<CapabilityHandle>> {
+    if len > CONVERSATION_MAX_CAPABILITIES {
+        return Err(SyscallError::WrongParameters);
+    }
+    check_addr_userspace(ptr)?;
+    check_addr_userspace(ptr + len * size_of::<u64>())?;
+
+    let mut handles = Vec::new();
+    handles.try_reserve_exact(len)?;
+    for i in 0..len {
+        // Userspace gives no alignment guarantees
+        let handle = unsafe { (ptr as *const u64).add(i).read_unaligned() };
+        handles.push(handle as CapabilityHandle);
+    }
+    Ok(handles)
+}
+
+pub fn talk(handle: CapabilityHandle, ptr: usize, len: usize) -> SyscallResult<()> {
+    let data = read_message_data(ptr, len)?;
+    let endpoint = get_endpoint(handle)?;
+
+    endpoint.send(Message {
+        sender: current_process_id(),
+        data,
+        capabilities: Vec::new(),
+    })
+}
+
+pub fn talk_capabilities(handle: CapabilityHandle, ptr: usize, len: usize, caps_ptr: usize, caps_len: usize, transfer: bool) -> SyscallResult<()> {
+    let data = read_message_data(ptr, len)?;
+    let handles = read_capability_handles(caps_ptr, caps_len)?;
+
+    let sender = current_process_id();
+    let task_lock = current_task();
+    let task = task_lock.read();
+    let mut caps = task.capabilities.lock();
+    let endpoint = match &caps.get(handle).ok_or(SyscallError::WrongCapability)?.ctype {
+        CapabilityType::Conversation(x) => x.clone(),
+        _ => return Err(SyscallError::WrongCapability),
+    };
+    // In a public conversation anyone could hear the message, so nobody would be the owner
+    if transfer && endpoint.kind() != ConversationKind::P2p {
+        return Err(SyscallError::WrongCapability);
+    }
+
+    // Check every capability before touching anything, the message is either sent whole or not at all
+    let needed = if transfer { CapabilityPerms::TRANSFER } else { CapabilityPerms::SHAREABLE };
+    let mut capabilities = Vec::new();
+    capabilities.try_reserve_exact(handles.len())?;
+    for (i, &cap_handle) in handles.iter().enumerate() {
+        if transfer && handles[..i].contains(&cap_handle) {
+            // A capability can only be transferred once
+            return Err(SyscallError::WrongParameters);
+        }
+        let cap = caps.get(cap_handle).ok_or(SyscallError::WrongCapability)?;
+        if !cap.perms.contains(needed) {
+            return Err(SyscallError::WrongCapabilityPerms);
+        }
+        capabilities.push(cap.clone());
+    }
+
+    endpoint.send(Message {
+        sender,
+        data,
+        capabilities,
+    })?;
+
+    if transfer {
+        for cap_handle in handles {
+            caps.delete(cap_handle)?;
+        }
+    }
+    Ok(())
+}
+
+pub fn listen(handle: CapabilityHandle, ptr: usize, len: usize, caps_ptr: usize, caps_len: usize, mode: usize) -> SyscallResult<(usize, usize, usize)> {
+    // TODO: is it safe to write?
+    check_addr_userspace(ptr)?;
+    check_addr_userspace(ptr + len)?;
+    check_addr_userspace(caps_ptr)?;
+    check_addr_userspace(caps_ptr + caps_len * size_of::<u64>())?;
+    let buffer = unsafe { core::slice::from_raw_parts_mut(ptr as *mut u8, len) };
+    let mode = ConversationListenMode::from_bits_truncate(mode as u8);
+
+    let endpoint = get_endpoint(handle)?;
+    let message = loop {
+        match endpoint.receive(len, caps_len) {
+            Err(SyscallError::ConversationEmpty) if !mode.contains(ConversationListenMode::NON_BLOCKING) => {
+                endpoint.wait_message()?;
+            }
+            x => break x?,
+        }
+    };
+
+    let task_lock = current_task();
+    let task = task_lock.read();
+    let mut caps = task.capabilities.lock();
+    // Reserve first, after this inserting the capabilities cannot fail
+    if let Err(e) = caps.handles.try_reserve(message.capabilities.len()) {
+        endpoint.give_back(message);
+        return Err(e.into());
+    }
+
+    buffer[..message.data.len()].copy_from_slice(&message.data);
+    let caps_count = message.capabilities.len();
+    for (i, cap) in message.capabilities.into_iter().enumerate() {
+        let cap_handle = caps.insert(cap)?;
+        unsafe { (caps_ptr as *mut u64).add(i).write_unaligned(cap_handle as u64) };
+    }
+    Ok((message.data.len(), message.sender.0.get() as usize, caps_count))
+}
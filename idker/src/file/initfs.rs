@@ -24,7 +24,7 @@ impl InitFsFolderHandle {
     }
 
     fn rel_open(self, x: &str) -> Result<Self, PathOpenError> {
-        if self.file.is_none() {
+        if self.file.is_some() {
             return Err(PathOpenError::PathIsFile);
         }
 
@@ -73,13 +73,7 @@ impl PathHandle for InitFsFolderHandle {
 
     fn read(&self) -> Box<dyn FileHandle> {
         match self.file {
-            Some(file) => {
-                Box::new(InitFsFileHandle {
-                    //path: self.path.clone() + file.0,
-                    file: file.1,
-                    index: 0,
-                })
-            },
+            Some(file) => Box::new(InitFsFileHandle::new(file.1)),
             None => dir_names_to_handle(
                 self.folder.0.iter().map(|x| x.0)
             ),
@@ -88,11 +82,19 @@ impl PathHandle for InitFsFolderHandle {
 }
 
 pub struct InitFsFileHandle {
-    //path: String,
     file: &'static [u8],
     index: usize,
 }
 
+impl InitFsFileHandle {
+    pub fn new(file: &'static [u8]) -> Self {
+        InitFsFileHandle {
+            file,
+            index: 0,
+        }
+    }
+}
+
 impl FileHandle for InitFsFileHandle {
     fn seek(&mut self, at: usize) -> Result<(), FileHandleError> {
         if at > self.file.len() {
@@ -105,7 +107,15 @@ impl FileHandle for InitFsFileHandle {
 
     fn read(&mut self, at: &mut [u8]) -> usize {
         let len = min(at.len(), self.file.len() - self.index);
-        at[..len].copy_from_slice(&self.file[self.index..len]);
+        at[..len].copy_from_slice(&self.file[self.index..][..len]);
+        self.index += len;
         len
     }
+
+    fn duplicate(&self) -> Box<dyn FileHandle> {
+        Box::new(InitFsFileHandle {
+            file: self.file,
+            index: self.index,
+        })
+    }
 }
@@ -8,6 +8,7 @@ use core::cmp::min;
 use alloc::vec::Vec;
 pub use system::{PathHandle, PathNavigator, PathType, PathOpenError, FileHandle, FileHandleError};
 pub use initfs::{InitFsFolderHandle, InitFsFileHandle};
+pub use procfs::ProcRootFs;
 
 use ::syscall::{SyscallError, SyscallResult};
 
@@ -21,7 +22,7 @@ pub fn read_file_to_memory(fh: &mut dyn FileHandle, limit: usize) -> SyscallResu
         }
         buffer.try_reserve(read_n)?;
         let old_len = buffer.len();
-        buffer.resize(old_len, 0);
+        buffer.resize(old_len + read_n, 0);
         let slice = &mut buffer[old_len..];
         let read = fh.read(slice);
         if read != read_n {
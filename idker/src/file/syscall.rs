@@ -1,13 +1,13 @@
 use core::{num::NonZeroUsize, slice};
 
-use alloc::{boxed::Box, sync::Arc, vec::Vec};
+use alloc::{boxed::Box, string::String, sync::Arc, vec::Vec};
 use spin::RwLock;
 use syscall::FsOpenMode;
 
 use crate::{context::{TaskId, current_task}, syscalls::{check_addr_userspace}};
 use ::syscall::{SyscallError, SyscallResult};
 
-use super::{procfs::{ProcRootFs, ProcRootPathHandle}, system::{FileHandle, open_path_full}};
+use super::{FileHandleError, procfs::{ProcRootFs, ProcRootPathHandle}, system::{FileHandle, open_path_full}};
 
 pub type FileDescriptor = NonZeroUsize;
 
@@ -23,6 +23,14 @@ impl TaskFileStorage {
         self.next_descriptor = NonZeroUsize::new(x.get() + 1).unwrap();
         x
     }
+
+    /// Adds handles that keep their descriptors, the storage must be empty (ex. in a new process)
+    pub fn inherit(&mut self, handles: Vec<(FileDescriptor, Box<dyn FileHandle>)>) {
+        if let Some(last) = handles.iter().map(|x| x.0).max() {
+            self.next_descriptor = NonZeroUsize::new(last.get() + 1).unwrap();
+        }
+        self.handles = handles;
+    }
 }
 
 impl TaskFileStorage {
@@ -35,6 +43,50 @@ impl TaskFileStorage {
     }
 }
 
+/// A file opened by the process, it remembers its path (a #! script executed from its descriptor
+/// is passed to the interpreter by path)
+pub struct NamedFileHandle {
+    path: String,
+    file: Box<dyn FileHandle>,
+}
+
+impl NamedFileHandle {
+    pub fn new(path: &str, file: Box<dyn FileHandle>) -> SyscallResult<Self> {
+        let mut owned = String::new();
+        owned.try_reserve_exact(path.len())?;
+        owned.push_str(path);
+        Ok(NamedFileHandle { path: owned, file })
+    }
+}
+
+impl FileHandle for NamedFileHandle {
+    fn seek(&mut self, at: usize) -> Result<(), FileHandleError> {
+        self.file.seek(at)
+    }
+
+    fn read(&mut self, at: &mut [u8]) -> usize {
+        self.file.read(at)
+    }
+
+    fn duplicate(&self) -> Box<dyn FileHandle> {
+        Box::new(NamedFileHandle {
+            path: self.path.clone(),
+            file: self.file.duplicate(),
+        })
+    }
+
+    fn path(&self) -> Option<&str> {
+        Some(&self.path)
+    }
+}
+
+/// Opens a file for reading, path starts from root (the root of a process, see TaskFileStorage)
+pub fn open_for_read(root: &Arc<RwLock<ProcRootFs>>, path: &str) -> SyscallResult<Box<dyn FileHandle>> {
+    let root_handle = ProcRootPathHandle(root.clone());
+    let path_handle = open_path_full(Arc::new(root_handle), path)?;
+    Ok(path_handle.read())
+}
+
 pub fn open(path_ptr: usize, path_len: usize, mode: usize) -> SyscallResult<FileDescriptor> {
     check_addr_userspace(path_ptr)?;
     check_addr_userspace(path_ptr + path_len)?;
@@ -47,19 +99,19 @@ pub fn open(path_ptr: usize, path_len: usize, mode: usize) -> SyscallResult<File
     }
 
     let task_lock = current_task();
-    let mut task = task_lock.write();
-    let root_handle = ProcRootPathHandle(task.files.root.clone());
-    let path_handle = open_path_full(Arc::new(root_handle), path)?;
-    let file_handle = path_handle.read();
-    let descriptor = task.files.allocate_descriptor();
-    task.files.handles.push((descriptor, file_handle));
+    let task = task_lock.read();
+    let mut files = task.files.lock();
+    let file_handle = Box::new(NamedFileHandle::new(path, open_for_read(&files.root, path)?)?);
+    let descriptor = files.allocate_descriptor();
+    files.handles.push((descriptor, file_handle));
     Ok(descriptor)
 }
 
 pub fn seek(fd: usize, index: usize) -> SyscallResult<()> {
     let task_lock = current_task();
-    let mut task = task_lock.write();
-    let handle = &mut task.files.handles.iter_mut()
+    let task = task_lock.read();
+    let mut files = task.files.lock();
+    let handle = &mut files.handles.iter_mut()
             .find(|x| x.0.get() == fd)
             .ok_or(SyscallError::WrongDescriptor)?.1;
 
@@ -74,8 +126,9 @@ pub fn read(fd: usize, at: usize, length: usize) -> SyscallResult<usize> {
     let slice = unsafe { core::slice::from_raw_parts_mut(at as *mut u8, length) };
 
     let task_lock = current_task();
-    let mut task = task_lock.write();
-    let handle = &mut task.files.handles.iter_mut()
+    let task = task_lock.read();
+    let mut files = task.files.lock();
+    let handle = &mut files.handles.iter_mut()
             .find(|x| x.0.get() == fd)
             .ok_or(SyscallError::WrongDescriptor)?.1;
 
@@ -84,11 +137,12 @@ pub fn read(fd: usize, at: usize, length: usize) -> SyscallResult<usize> {
 
 pub fn close(fd: usize) -> SyscallResult<()> {
     let task_lock = current_task();
-    let mut task = task_lock.write();
-    let index = task.files.handles.iter()
+    let task = task_lock.read();
+    let mut files = task.files.lock();
+    let index = files.handles.iter()
             .position(|x| x.0.get() == fd)
             .ok_or(SyscallError::WrongDescriptor)?;
 
-    task.files.handles.remove(index);
+    files.handles.remove(index);
     Ok(())
 }
\ No newline at end of file
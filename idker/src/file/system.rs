@@ -58,6 +58,14 @@ pub trait FileHandle : Send + Sync {
     fn seek(&mut self, at: usize) -> Result<(), FileHandleError>;
 
     fn read(&mut self, at: &mut [u8]) -> usize;
+
+    /// Another handle to the same file, at the same position but moving on its own
+    fn duplicate(&self) -> Box<dyn FileHandle>;
+
+    /// Path the file was opened with (from the root of the process), if the handle knows it
+    fn path(&self) -> Option<&str> {
+        None
+    }
 }
 
 pub trait PathNavigator {
@@ -88,7 +96,7 @@ impl<'a> PathNavigator for str {
 
 
 
-pub fn dir_names_to_handle(x: impl Iterator<Item = &'static str> + 'static + Send + Sync) -> Box<dyn FileHandle> {
+pub fn dir_names_to_handle(x: impl Iterator<Item = &'static str> + Clone + 'static + Send + Sync) -> Box<dyn FileHandle> {
     const SEPAR_SLICE: &'static[&'static[u8]] = &[b"\n".as_slice()];
 
     let iter = x.map(|x| x.as_bytes())
@@ -103,7 +111,7 @@ pub struct IteratorFileHandle<T: Iterator<Item = u8> + FusedIterator + Send>(T);
 
 
 impl<T> FileHandle for IteratorFileHandle<T>
-        where T : Iterator<Item = u8> + FusedIterator + Send + Sync {
+        where T : Iterator<Item = u8> + FusedIterator + Clone + Send + Sync + 'static {
     fn seek(&mut self, _at: usize) -> Result<(), FileHandleError> {
         Err(FileHandleError::NotSeekable)
     }
@@ -116,4 +124,8 @@ impl<T> FileHandle for IteratorFileHandle<T>
         }
         i
     }
+
+    fn duplicate(&self) -> Box<dyn FileHandle> {
+        Box::new(IteratorFileHandle(self.0.clone()))
+    }
 }
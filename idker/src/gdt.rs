@@ -51,6 +51,11 @@ unsafe fn init_tss() {
     };
 }
 
+/// Sets the stack used by interrupts coming from userspace (each task has its own)
+pub unsafe fn set_user_interrupt_stack(stack_end: VirtAddr) {
+    TSS.privilege_stack_table[0] = stack_end;
+}
+
 unsafe fn init_gdt() -> Selectors {
     init_tss();
 
@@ -1,9 +1,12 @@
 use lazy_static::lazy_static;
+use memoffset::offset_of;
 use pic8259::ChainedPics;
 use spin;
-use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::{PrivilegeLevel, VirtAddr, structures::idt::{InterruptDescriptorTable, InterruptStackFrame, InterruptStackFrameValue, PageFaultErrorCode}};
 
-use crate::{gdt, hlt_loop, print, println};
+use ::syscall::{ExitReason, FaultKind};
+
+use crate::{arch::{apic::{LOCAL_APIC, SPURIOUS_VECTOR}, tlb::{self, TLB_SHOOTDOWN_VECTOR}}, context::{current_task_id, exit_current_process, fault, on_user_tick}, gdt, hlt_loop, println, time, syscalls::{KERNEL_FS_BASE, TCD, ThreadControlData, asm::{AllSavedRegisters, load_all_regs, load_kernel_fs, load_user_fs, save_all_regs}}};
 
 pub const PIC_1_OFFSET: u8 = 32;
 pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
@@ -11,10 +14,13 @@ pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 #[derive(Debug, Clone, Copy)]
 #[repr(u8)]
 pub enum InterruptIndex {
-    Timer = PIC_1_OFFSET,
+    Timer = PIC_1_OFFSET,// Fired by the local APIC timer (the PIC timer is masked)
     Keyboard,
 }
 
+// Period of the scheduler tick
+pub const SCHEDULER_TICK_US: u64 = 10_000;
+
 impl InterruptIndex {
     fn as_u8(self) -> u8 {
         self as u8
@@ -31,22 +37,32 @@ pub static PICS: spin::Mutex<ChainedPics> =
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
-
-        idt.general_protection_fault
-            .set_handler_fn(general_protection_fault_handler);
         idt.segment_not_present
             .set_handler_fn(segment_not_present_handler);
         unsafe {
-            idt.page_fault
-                .set_handler_fn(page_fault_handler)
-                .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+            idt.divide_error.set_handler_addr(VirtAddr::new(divide_error_entry as u64));
+            // int3 can be used by userspace
+            idt.breakpoint.set_handler_addr(VirtAddr::new(breakpoint_entry as u64))
+                .set_privilege_level(PrivilegeLevel::Ring3);
+            idt.invalid_opcode.set_handler_addr(VirtAddr::new(invalid_opcode_entry as u64));
+            idt.general_protection_fault.set_handler_addr(VirtAddr::new(general_protection_fault_entry as u64));
+            // No IST here: a task waiting for its fault port is switched out while in the handler,
+            // so it needs to stay on its own kernel stack
+            idt.page_fault.set_handler_addr(VirtAddr::new(page_fault_entry as u64));
+            idt.x87_floating_point.set_handler_addr(VirtAddr::new(x87_floating_point_entry as u64));
+            idt.simd_floating_point.set_handler_addr(VirtAddr::new(simd_floating_point_entry as u64));
             idt.double_fault
                 .set_handler_fn(double_fault_handler)
                 .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
         }
-        idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler);
+        unsafe {
+            idt[InterruptIndex::Timer.as_usize()].set_handler_addr(VirtAddr::new(timer_interrupt_entry as u64));
+        }
         idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
+        unsafe {
+            idt[TLB_SHOOTDOWN_VECTOR as usize].set_handler_addr(VirtAddr::new(tlb_shootdown_entry as u64));
+        }
+        idt[SPURIOUS_VECTOR as usize].set_handler_fn(spurious_interrupt_handler);
         idt
     };
 }
@@ -55,42 +71,147 @@ pub fn init_idt() {
     IDT.load();
 }
 
-extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
-    println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
+/// Starts the scheduler tick on the current core (its local APIC must be initialized)
+pub fn start_scheduler_tick() {
+    unsafe {
+        // The legacy timer would fire on the same vector
+        let mut pics = PICS.lock();
+        let [mask1, mask2] = pics.read_masks();
+        pics.write_masks(mask1 | 1, mask2);
+        drop(pics);
+
+        LOCAL_APIC.set_timer_periodic(InterruptIndex::Timer.as_u8(), SCHEDULER_TICK_US);
+    }
 }
 
-extern "x86-interrupt" fn double_fault_handler(
-    stack_frame: InterruptStackFrame,
-    error_code: u64,
-) -> ! {
-    panic!(
-        "EXCEPTION: DOUBLE FAULT\n{:#?}\nError code: {}",
-        stack_frame, error_code
-    );
+// The x86-interrupt ABI hides the general purpose registers, but a fault port might want to read
+// or change them, and a preempted task must be resumed exactly where it was, so the interrupts
+// that can come from userspace have a hand-written entry that saves everything.
+// Every entry pushes the same frame (the CPU doesn't push an error code for all of them).
+#[derive(Debug)]
+#[repr(C)]
+pub struct ExceptionFrame {
+    pub error_code: u64,
+    pub frame: InterruptStackFrameValue,
 }
 
-extern "x86-interrupt" fn page_fault_handler(
-    stack_frame: InterruptStackFrame,
-    error_code: PageFaultErrorCode,
-) {
+macro_rules! interrupt_entry {
+    ($name:ident, $handler:ident, $vector:expr, $push_error_code:expr) => {
+        #[naked]
+        unsafe extern "C" fn $name() {
+            core::arch::asm!(concat!(
+                    $push_error_code,
+                    // Coming from userspace (CS pushed by the cpu)? Then switch to the kernel GS and FS
+                    "test qword ptr [rsp + 16], 3\n",
+                    "jz 2f\n",
+                    "swapgs\n",
+                    "2:\n",
+                    save_all_regs!(),
+                    "test qword ptr [rsp + {cs_offset}], 3\n",
+                    "jz 3f\n",
+                    load_kernel_fs!(),
+                    "3:\n",
+                    "cld\n",
+                    "mov rdi, rsp\n",// regs
+                    "lea rsi, [rsp + {regs_size}]\n",// frame
+                    "mov rdx, {vector}\n",
+                    "call {handler}\n",
+                    "cli\n",// The handler might have enabled them
+                    "test qword ptr [rsp + {cs_offset}], 3\n",
+                    "jz 4f\n",
+                    load_user_fs!(),
+                    "4:\n",
+                    load_all_regs!(),
+                    "add rsp, 8\n",// Pop the error code
+                    "test qword ptr [rsp + 8], 3\n",
+                    "jz 5f\n",
+                    "swapgs\n",
+                    "5:\n",
+                    "iretq\n"),
+                regs_size = const core::mem::size_of::<AllSavedRegisters>(),
+                cs_offset = const core::mem::size_of::<AllSavedRegisters>() + 16,
+                tcd = sym TCD,
+                kernel_fs = sym KERNEL_FS_BASE,
+                user_fs_offset = const offset_of!(ThreadControlData, user_fs_base),
+                vector = const $vector,
+                handler = sym $handler,
+                options(noreturn)
+            )
+        }
+    };
+}
+
+interrupt_entry!(divide_error_entry, on_exception, 0, "push 0\n");
+interrupt_entry!(breakpoint_entry, on_exception, 3, "push 0\n");
+interrupt_entry!(invalid_opcode_entry, on_exception, 6, "push 0\n");
+interrupt_entry!(general_protection_fault_entry, on_exception, 13, "");
+interrupt_entry!(page_fault_entry, on_exception, 14, "");
+interrupt_entry!(x87_floating_point_entry, on_exception, 16, "push 0\n");
+interrupt_entry!(simd_floating_point_entry, on_exception, 19, "push 0\n");
+interrupt_entry!(timer_interrupt_entry, on_timer, InterruptIndex::Timer as u8, "push 0\n");
+// Might interrupt userspace, the handler needs the kernel FS to know which cpu it's on
+interrupt_entry!(tlb_shootdown_entry, on_tlb_shootdown, TLB_SHOOTDOWN_VECTOR, "push 0\n");
+
+extern "C" fn on_exception(regs: &mut AllSavedRegisters, frame: &mut ExceptionFrame, vector: u64) {
     use x86_64::registers::control::Cr2;
 
-    println!("EXCEPTION: PAGE FAULT");
-    println!("Accessed Address: {:?}", Cr2::read());
-    println!("Error Code: {:?}", error_code);
-    println!("{:#?}", stack_frame);
-    hlt_loop();
+    let kind = FaultKind::from_vector(vector).expect("Unknown exception vector");
+    let cr2 = match kind {
+        FaultKind::PageFault => Cr2::read().as_u64(),
+        _ => 0,
+    };
+
+    // The privilege level we were in is in the low bits of the saved CS
+    if frame.frame.code_segment & 3 == 3 {
+        if !fault::deliver_fault(regs, frame, kind, cr2) {
+            kill_faulting_task(frame, kind, cr2);
+        }
+        return;
+    }
+
+    match kind {
+        FaultKind::Breakpoint => {
+            println!("EXCEPTION: BREAKPOINT\n{:#?}", frame.frame);
+        }
+        FaultKind::PageFault => {
+            println!("EXCEPTION: PAGE FAULT");
+            println!("Accessed Address: {:#x}", cr2);
+            println!("Error Code: {:?}", PageFaultErrorCode::from_bits_truncate(frame.error_code));
+            println!("{:#?}", frame.frame);
+            hlt_loop();
+        }
+        FaultKind::GeneralProtection => {
+            println!(
+                "EXCEPTION: GENERAL PROTECTION FAULT\n{:#?}\nError code: {:?}",
+                frame.frame, frame.error_code
+            );
+            hlt_loop();
+        }
+        _ => panic!("EXCEPTION: {:?}\n{:#?}", kind, frame.frame),
+    }
+}
+
+/// A user program did something bad, kill it and leave the machine to the others
+fn kill_faulting_task(frame: &ExceptionFrame, kind: FaultKind, cr2: u64) -> ! {
+    let rip = frame.frame.instruction_pointer.as_u64();
+    let address = match kind {
+        FaultKind::PageFault => cr2,
+        // The error code is the faulting segment selector (if any)
+        FaultKind::GeneralProtection => frame.error_code,
+        _ => rip,
+    };
+    println!("Task {} killed by {:?} (address: {:#x}, rip: {:#x})", current_task_id().0, kind, address, rip);
+    exit_current_process(ExitReason::Faulted { kind, address, rip })
 }
 
-extern "x86-interrupt" fn general_protection_fault_handler(
+extern "x86-interrupt" fn double_fault_handler(
     stack_frame: InterruptStackFrame,
     error_code: u64,
-) -> () {
-    println!(
-        "EXCEPTION: GENERAL PROTECTION FAULT\n{:#?}\nError code: {:?}",
+) -> ! {
+    panic!(
+        "EXCEPTION: DOUBLE FAULT\n{:#?}\nError code: {}",
         stack_frame, error_code
     );
-    hlt_loop();
 }
 
 extern "x86-interrupt" fn segment_not_present_handler(
@@ -104,14 +225,24 @@ extern "x86-interrupt" fn segment_not_present_handler(
     hlt_loop();
 }
 
-extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    print!(".");
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
+extern "C" fn on_timer(_regs: &mut AllSavedRegisters, frame: &mut ExceptionFrame, _vector: u64) {
+    unsafe { LOCAL_APIC.eoi() };
+    // The kernel is not preemptible, only tasks running in userspace can be switched out
+    if frame.frame.code_segment & 3 == 3 {
+        time::process_timers();
+        on_user_tick();
     }
 }
 
+extern "C" fn on_tlb_shootdown(_regs: &mut AllSavedRegisters, _frame: &mut ExceptionFrame, _vector: u64) {
+    tlb::on_shootdown();
+    unsafe { LOCAL_APIC.eoi() };
+}
+
+extern "x86-interrupt" fn spurious_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    // Nothing to do, spurious interrupts must not be acknowledged
+}
+
 extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
     use x86_64::instructions::port::Port;
 
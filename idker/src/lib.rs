@@ -19,12 +19,13 @@
 extern crate alloc;
 
 #[cfg(test)]
-use bootloader::{entry_point, BootInfo};
+use bootloader_api::{entry_point, BootInfo};
 use core::panic::PanicInfo;
 
 pub mod allocator;
 pub mod arch;
 pub mod context;
+pub mod conversation;
 pub mod file;
 pub mod gdt;
 pub mod utils;
@@ -34,6 +35,7 @@ pub mod serial;
 pub mod capability;
 pub mod syscalls;
 pub mod task;
+pub mod time;
 pub mod vga_framebuffer;
 
 #[cfg(test)]
@@ -41,8 +43,9 @@ entry_point!(test_kernel_main);
 
 /// Entry point for `cargo test`
 #[cfg(test)]
-fn test_kernel_main(boot_info: &'static mut BootInfo) -> ! {
+fn test_kernel_main(_boot_info: &'static mut BootInfo) -> ! {
     init();
+    allocator::init_test_heap();
     test_main();
     hlt_loop();
 }
@@ -53,6 +56,8 @@ pub fn init() {
     unsafe {
         let mut pic = interrupts::PICS.lock();
         pic.initialize();
+        // The scheduler tick comes from the local APIC timer (see interrupts::start_scheduler_tick)
+        pic.write_masks(0b0000_0001, 0);
     };
     x86_64::instructions::interrupts::enable();
 }
@@ -17,7 +17,7 @@ use kerneltest::{allocator, allocator::get_frame_allocator, arch::{
             self, fix_bootloader_pollution,
             globalize_kernelspace,
         },
-    }, context::{TaskContext, set_current_task_id, switch_to_next_task, tasks_mut}, file::InitFsFolderHandle, gdt, println, syscalls::{self, start_initproc}, vga_framebuffer::init_vga_framebuffer};
+    }, capability::{Capability, CapabilityPerms, CapabilityType}, context::{TaskContext, become_idle_task, idle_loop, scheduler, tasks_mut}, file::InitFsFolderHandle, gdt, interrupts, println, syscalls::{self, start_initproc}, vga_framebuffer::init_vga_framebuffer};
 
 
 pub static BOOTLOADER_CONFIG: BootloaderConfig = {
@@ -64,6 +64,7 @@ fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
     }
     println!("Setting up GDT");
     gdt::init();
+    kerneltest::arch::fpu::init();
 
     #[cfg(test)]
     test_main();
@@ -74,6 +75,8 @@ fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
     }
 
     kerneltest::arch::acpi::init(boot_info.rsdp_addr.into_option().expect("Cannot find rsdp"));
+    kerneltest::time::init();
+    interrupts::start_scheduler_tick();
     syscalls::setup_syscalls();
 
     run_executor()
@@ -84,25 +87,40 @@ fn run_executor() -> ! {
         // Init task = main kernel task (with no user thread attached)
         let init = TaskContext::create_init();
         let init_id = init.id;
-        tasks_mut().add(init);
-        set_current_task_id(init_id);
+        become_idle_task(init);
 
         // initproc task = a normal task used to run the init process
         let ctx = TaskContext::create(init_id, start_initproc);
         let ctx_id = ctx.id;
+        let ctx_affinity = ctx.affinity;
+        let ctx_priority = ctx.priority;
         {// Add init file system
-            let mut root = ctx.files.root.write();
+            let files = ctx.files.lock();
+            let mut root = files.root.write();
             root.mount("init", Arc::new(InitFsFolderHandle::from_init_dir("init".into())));
         }
+        {// Root capabilities
+            let mut caps = ctx.capabilities.lock();
+            caps.insert(Capability {
+                perms: CapabilityPerms::all(),
+                ctype: CapabilityType::ChannelCreate,
+            }).expect("Cannot give capabilities to initproc");
+            caps.insert(Capability {
+                perms: CapabilityPerms::all(),
+                ctype: CapabilityType::SchedulerPriority,
+            }).expect("Cannot give capabilities to initproc");
+        }
         let mut tasks = tasks_mut();
         tasks.add(ctx);
-        tasks.queue_for_execution(ctx_id);
-    }
-    loop {
-        if !switch_to_next_task() {
-            x86_64::instructions::interrupts::enable_and_hlt();
-        }
+        tasks.orphan_reaper = Some(ctx_id);
+        drop(tasks);
+        scheduler::enqueue(ctx_id, ctx_affinity, ctx_priority);
     }
+    // The registry is ready, the other cores can start running tasks too
+    #[cfg(feature = "multi_core")]
+    kerneltest::arch::multi_core::release_aps();
+
+    idle_loop()
 }
 
 #[cfg(not(test))]
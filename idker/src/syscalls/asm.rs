@@ -65,10 +65,39 @@ macro_rules! load_all_regs {
 
 do_with_all_regs!(create_specified_reg_struct, AllSavedRegisters, rflags);
 
+// The kernel keeps its thread locals in FS like userspace does, so the FS base is swapped every time
+// we enter or leave userspace. While in the kernel GS base points to the same block (swapgs keeps
+// the user one in KernelGsBase), that's how the entry code finds the kernel data.
+// Both clobber rax, rcx and rdx and need the kernel_fs, tcd and user_fs_offset operands.
+
+macro_rules! load_kernel_fs {
+    () => {
+        concat!(
+            "mov ecx, 0xC0000100\n",// IA32_FS_BASE
+            "mov eax, gs:[{kernel_fs}@tpoff+8]\n",
+            "mov edx, gs:[{kernel_fs}@tpoff+12]\n",
+            "wrmsr\n",
+        )
+    };
+}
+
+macro_rules! load_user_fs {
+    () => {
+        concat!(
+            "mov ecx, 0xC0000100\n",// IA32_FS_BASE
+            "mov eax, gs:[{tcd}@tpoff+{user_fs_offset}+8]\n",
+            "mov edx, gs:[{tcd}@tpoff+{user_fs_offset}+12]\n",
+            "wrmsr\n",
+        )
+    };
+}
+
 pub(crate) use concat_reverse;
 pub(crate) use create_specified_reg_struct;
 pub(crate) use do_with_all_regs;
 pub(crate) use load_all_regs;
+pub(crate) use load_kernel_fs;
+pub(crate) use load_user_fs;
 pub(crate) use load_specified_regs;
 pub(crate) use save_all_regs;
 pub(crate) use save_specified_regs;
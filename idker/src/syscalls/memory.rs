@@ -1,11 +1,11 @@
 use core::convert::{TryFrom, TryInto};
 
-use crate::{capability::CapabilityType, arch::paging::get_page_table, allocator::get_frame_allocator, context::current_task};
+use crate::{capability::CapabilityType, allocator::get_frame_allocator, context::{UserPageTable, current_task}};
 
-use super::{SyscallResult, SyscallError, check_addr_userspace};
+use super::{SyscallResult, SyscallError, user_virt_addr};
 
 use bitflags::bitflags;
-use x86_64::{structures::paging::{Mapper, FrameAllocator, PageTableFlags, Page, Size4KiB, mapper::MapToError, page::PageRange, PhysFrame}, VirtAddr, PhysAddr};
+use x86_64::{structures::paging::{Mapper, FrameAllocator, PageTableFlags, Page, Size4KiB, mapper::MapToError, page::PageRange, PhysFrame}, PhysAddr};
 
 
 bitflags! {
@@ -35,45 +35,55 @@ impl TryFrom<MemoryPerms> for PageTableFlags {
 }
 
 fn parse_page_range(at: usize, len: usize) -> SyscallResult<PageRange> {
-    // Check userspace
-    check_addr_userspace(at)?;
-    check_addr_userspace(at + len - 1)?;
+    if len == 0 {
+        return Err(SyscallError::WrongParameters);
+    }
+    let end = at.checked_add(len).ok_or(SyscallError::WrongParameters)?;
+    // Check userspace, the end must be an address too (so the last page of userspace can't be used)
+    let at = user_virt_addr(at)?;
+    let end = user_virt_addr(end)?;
     // Check page aligment
-    let at = VirtAddr::new(at as u64);
     let start_page = Page::<Size4KiB>::from_start_address(at)
             .map_err(|_| SyscallError::WrongParameters)?;
 
-    let end_page = Page::<Size4KiB>::from_start_address(at + len)
+    let end_page = Page::<Size4KiB>::from_start_address(end)
         .map_err(|_| SyscallError::WrongParameters)?;
 
     Ok(Page::range(start_page, end_page))
 }
 
-pub fn map_virt(at: usize, len: usize, perms: usize) -> SyscallResult<()> {
-    let page_range = parse_page_range(at, len)?;
-
+pub fn map_virt(at: usize, len: usize, perms: usize) -> SyscallResult<usize> {
     // Check capability
     let task_guard = current_task();
     let task = task_guard.read();
-    task.capabilities.handles.iter()
+    let caps = task.capabilities.lock();
+    caps.handles.iter()
             .find(|x| x.1.ctype == CapabilityType::MapVirtualToRam)
             .ok_or(SyscallError::WrongCapability)?;
+    drop(caps);
 
     let perms = MemoryPerms::from_bits_truncate(perms as u8);
+    // BIT_10: the frame is allocated here, MemoryUnmap will free it
     let flags = PageTableFlags::try_from(perms)? | PageTableFlags::BIT_10;
     let parent_table_flags = PageTableFlags::PRESENT |
             PageTableFlags::USER_ACCESSIBLE | PageTableFlags::WRITABLE;
 
     // TODO: Should the operation be atomic?
-    // TODO: what about multiple threads?
     // Optimistic behaviour: first allocate assuming the paging is free, then try to map
 
+    // The page table is shared by all the threads of the process
+    let mut user_table = task.page_table.lock();
+    // At 0 the kernel chooses, right after the previous mapping it chose
+    let page_range = match at {
+        0 => parse_page_range(user_table.mmap_next as usize, len)?,
+        _ => parse_page_range(at, len)?,
+    };
+    let mut table = user_table.offset_page();
     let mut frame_allocator = get_frame_allocator();
     // TODO: can we use map_to_with_table_flags? what parent flags should we use?
     // what if it's already mapped?
-    for page in page_range {
+    for page in page_range.clone() {
         let frame = frame_allocator.allocate_frame().ok_or(SyscallError::NoMemory)?;
-        let mut table = get_page_table();
 
         unsafe {
             match table.map_to_with_table_flags(page, frame, flags, parent_table_flags, &mut *frame_allocator) {
@@ -83,8 +93,12 @@ pub fn map_virt(at: usize, len: usize, perms: usize) -> SyscallResult<()> {
             };
         }
     }
+    // A failed mapping doesn't move the next address
+    if at == 0 {
+        user_table.mmap_next = page_range.end.start_address().as_u64();
+    }
 
-    Ok(())
+    Ok(page_range.start.start_address().as_u64() as usize)
 }
 
 pub fn map_phys(virt_at: usize, virt_len: usize, perms: usize, phys_at: usize) -> SyscallResult<()> {
@@ -101,7 +115,8 @@ pub fn map_phys(virt_at: usize, virt_len: usize, perms: usize, phys_at: usize) -
     // Check capability
     let task_guard = current_task();
     let task = task_guard.read();
-    task.capabilities.handles.iter()
+    let caps = task.capabilities.lock();
+    caps.handles.iter()
             .find(|x| {
                 return match x.1.ctype {
                     CapabilityType::MapPhysical(f, t) => f <= phys_from && t >= phys_to,
@@ -109,6 +124,7 @@ pub fn map_phys(virt_at: usize, virt_len: usize, perms: usize, phys_at: usize) -
                 }
             })
             .ok_or(SyscallError::WrongCapability)?;
+    drop(caps);
 
     let perms = MemoryPerms::from_bits_truncate(perms as u8);
     let flags = perms.try_into()?;
@@ -116,13 +132,13 @@ pub fn map_phys(virt_at: usize, virt_len: usize, perms: usize, phys_at: usize) -
             PageTableFlags::USER_ACCESSIBLE | PageTableFlags::WRITABLE;
 
     // TODO: Should the operation be atomic?
-    // TODO: what about multiple threads?
 
+    let mut user_table = task.page_table.lock();
+    let mut table = user_table.offset_page();
     let mut frame_allocator = get_frame_allocator();
     // TODO: can we use map_to_with_table_flags? what parent flags should we use?
     // what if it's already mapped?
     for (page, frame) in page_range.zip(frame_range) {
-        let mut table = get_page_table();
 
         unsafe {
             match table.map_to_with_table_flags(page, frame, flags, parent_table_flags, &mut *frame_allocator) {
@@ -134,4 +150,26 @@ pub fn map_phys(virt_at: usize, virt_len: usize, perms: usize, phys_at: usize) -
     }
 
     Ok(())
-}
\ No newline at end of file
+}
+
+pub fn unmap(at: usize, len: usize) -> SyscallResult<()> {
+    let page_range = parse_page_range(at, len)?;
+
+    let page_table = current_task().read().page_table.clone();
+    // Other threads of the process might be using it on other cpus, they'll be asked to flush
+    UserPageTable::unmap_shared(&page_table, page_range);
+    Ok(())
+}
+#[test_case]
+fn page_range_checked() {
+    let range = |at, len| parse_page_range(at, len)
+            .map(|x| (x.start.start_address().as_u64(), x.end.start_address().as_u64()));
+    assert_eq!(range(0x1000, 0x2000), Ok((0x1000, 0x3000)));
+    assert_eq!(range(0, 0), Err(SyscallError::WrongParameters));
+    assert_eq!(range(0x1000, 0x800), Err(SyscallError::WrongParameters));
+    assert_eq!(range(0x1800, 0x1000), Err(SyscallError::WrongParameters));
+    // Not canonical, in the kernel half or wrapping around
+    assert_eq!(range(0x0001_0000_0000_0000, 0x1000), Err(SyscallError::WrongParameters));
+    assert_eq!(range(0x7FFF_FFFF_F000, 0x1000), Err(SyscallError::WrongParameters));
+    assert_eq!(range(0xFFFF_FFFF_FFFF_F000, 0x1000), Err(SyscallError::WrongParameters));
+}
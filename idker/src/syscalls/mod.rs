@@ -3,22 +3,25 @@ use core::convert::TryFrom;
 use memoffset::offset_of;
 use x86_64::{
     registers::{
-        model_specific::{Efer, EferFlags, LStar}
+        model_specific::{Efer, EferFlags, LStar, SFMask},
+        rflags::RFlags,
     },
     VirtAddr,
 };
 
-use crate::{context::{current_task, switch_to_next_task, task::TaskState}, file::{FileHandleError, PathOpenError}, println};
+use crate::{context::{exit_if_requested, fault, futex, switch_to_next_task}, file::{FileHandleError, PathOpenError}, println};
 use super::capability::syscall as cap_call;
 use super::context::syscall as proc_call;
+use super::conversation::syscall as conv_call;
 use super::file::syscall as file_call;
+use super::time::syscall as time_call;
 use syscall::{SyscallCode, SyscallError, SyscallResult};
 
-mod asm;
+pub(crate) mod asm;
 mod memory;
 mod userspace;
 
-pub use userspace::{enter_userspace, start_initproc, check_addr_userspace};
+pub use userspace::{enter_userspace, start_initproc, check_addr_userspace, user_virt_addr};
 
 
 impl From<PathOpenError> for SyscallError {
@@ -36,19 +39,36 @@ impl From<FileHandleError> for SyscallError {
     }
 }
 
+#[derive(Clone, Copy, Debug)]
 pub struct ThreadControlData {
     pub user_stack_pointer: u64,
     pub kernel_stack_pointer: u64,
+    // Loaded when going back to userspace (GS is also kept in KernelGsBase while in the kernel)
+    pub user_fs_base: u64,
+    pub user_gs_base: u64,
+}
+
+impl ThreadControlData {
+    pub const fn new() -> Self {
+        ThreadControlData {
+            user_stack_pointer: 0,
+            kernel_stack_pointer: 0,
+            user_fs_base: 0,
+            user_gs_base: 0,
+        }
+    }
 }
 
 #[thread_local]
-pub static mut TCD: ThreadControlData = ThreadControlData {
-    user_stack_pointer: 0,
-    kernel_stack_pointer: 0,
-};
+pub static mut TCD: ThreadControlData = ThreadControlData::new();
+// FS base of the current cpu, restored when entering the kernel from userspace
+#[thread_local]
+pub static mut KERNEL_FS_BASE: u64 = 0;
 
 pub fn setup_syscalls() {
     LStar::write(VirtAddr::new(on_syscall_raw as u64));
+    // Interrupts stay disabled until the kernel FS base is loaded
+    SFMask::write(RFlags::INTERRUPT_FLAG | RFlags::DIRECTION_FLAG | RFlags::TRAP_FLAG);
     unsafe { Efer::write(Efer::read() | EferFlags::SYSTEM_CALL_EXTENSIONS) };
 }
 
@@ -79,13 +99,7 @@ extern "C" fn on_symcall_1(regs: &mut asm::AllSavedRegisters) {
 
     regs.rax = match sysnum {
         SyscallCode::Exit => {
-            {
-                let lock = current_task();
-                let mut task = lock.write();
-                task.state = TaskState::Dying;
-            }
-            switch_to_next_task();
-            Ok(())
+            proc_call::exit(a)
         }
         SyscallCode::Yield => {
             switch_to_next_task();
@@ -118,6 +132,25 @@ extern "C" fn on_symcall_1(regs: &mut asm::AllSavedRegisters) {
             cap_call::cdrop(a)
         }
 
+        SyscallCode::ConversationCreateP2p => {
+            conv_call::create_p2p().map(|(x, y)| { regs.rdi = x; regs.rsi = y; })
+        }
+        SyscallCode::ConversationCreatePublic => {
+            conv_call::create_public().map(|x| regs.rdi = x)
+        }
+        SyscallCode::ConversationTalk => {
+            conv_call::talk(a, b, c)
+        }
+        SyscallCode::ConversationCapShare => {
+            conv_call::talk_capabilities(a, b, c, d, e, false)
+        }
+        SyscallCode::ConversationCapTransfer => {
+            conv_call::talk_capabilities(a, b, c, d, e, true)
+        }
+        SyscallCode::ConversationListen => {
+            conv_call::listen(a, b, c, d, e, f).map(|(x, y, z)| { regs.rdi = x; regs.rsi = y; regs.rdx = z; })
+        }
+
         SyscallCode::ProcessMyPid => {
             proc_call::mypid().map(|x| regs.rdi = x.0.get() as usize)
         }
@@ -128,21 +161,93 @@ extern "C" fn on_symcall_1(regs: &mut asm::AllSavedRegisters) {
             cap_call::process_share_transfer(a, b, sysnum == SyscallCode::ProcessCapTransfer)
         }
         SyscallCode::ProcessExec => {
-            proc_call::exec(a, b)
+            proc_call::exec(a, b, c, d, e, f)
+        }
+        SyscallCode::ProcessWait => {
+            proc_call::wait(a, b).map(|(pid, reason)| {
+                let (tag, x, y) = reason.into_raw();
+                regs.rdi = pid.0.get() as usize;
+                regs.rsi = tag as usize;
+                regs.rdx = x as usize;
+                regs.r10 = y as usize;
+            })
+        }
+        SyscallCode::ProcessSetSubreaper => {
+            proc_call::set_subreaper(a)
+        }
+        SyscallCode::ProcessFaultPortSet => {
+            fault::set_port(a, b)
+        }
+        SyscallCode::ProcessFaultPortClear => {
+            fault::clear_port(a)
+        }
+        SyscallCode::ProcessSetAffinity => {
+            proc_call::set_affinity(a, b)
+        }
+        SyscallCode::ProcessSetPriority => {
+            proc_call::set_priority(a, b, c)
+        }
+        SyscallCode::ProcessSpawnExec => {
+            proc_call::spawn_exec(a).map(|x| regs.rdi = x.0.get() as usize)
         }
 
         SyscallCode::MemoryMapVirt => {
-            memory::map_virt(a, b, c)
+            memory::map_virt(a, b, c).map(|x| regs.rdi = x)
         }
         SyscallCode::MemoryMapPhys => {
             memory::map_phys(a, b, c, d)
         }
         SyscallCode::MemoryUnmap => {
-            Ok(())
+            memory::unmap(a, b)
+        }
+
+        SyscallCode::Sleep => {
+            time_call::sleep(a)
+        }
+        SyscallCode::SleepUntil => {
+            time_call::sleep_until_deadline(a)
+        }
+        SyscallCode::TimerCreate => {
+            time_call::create().map(|x| regs.rdi = x)
+        }
+        SyscallCode::TimerArm => {
+            time_call::arm(a, b, c)
+        }
+        SyscallCode::TimerCancel => {
+            time_call::cancel(a)
+        }
+        SyscallCode::TimerWait => {
+            time_call::wait(a, b)
+        }
+        SyscallCode::ClockGetTime => {
+            time_call::clock_get_time(a).map(|x| regs.rdi = x as usize)
+        }
+
+        SyscallCode::ThreadSpawn => {
+            proc_call::spawn_thread(a, b, c).map(|x| regs.rdi = x.0.get() as usize)
+        }
+        SyscallCode::ThreadExit => {
+            proc_call::thread_exit(a)
+        }
+        SyscallCode::ThreadMyTid => {
+            proc_call::mytid().map(|x| regs.rdi = x.0.get() as usize)
+        }
+        SyscallCode::ThreadSetTlsBase => {
+            proc_call::set_tls_base(a, b)
+        }
+
+        SyscallCode::FutexWait => {
+            futex::wait(a, b, c)
+        }
+        SyscallCode::FutexWake => {
+            futex::wake(a, b).map(|x| regs.rdi = x)
         }
 
         _ => Err(SyscallError::UnknownSyscall)
     }.map(|_| 0).unwrap_or_else(|x| x as usize);
+
+    // Another thread might have terminated the process while we were in the kernel
+    exit_if_requested();
 }
 
 macro_rules! concat_newl {
@@ -156,18 +261,24 @@ unsafe extern "C" fn on_syscall_raw() {
     // C args: rdi, rsi, rdx, rcx, r8, r9,  stack...
     core::arch::asm!(concat_newl!(
         "swapgs",
-        "mov fs:[{tcd}@tpoff+{sp_offset}+8], rsp",// save stack pointer
-        "mov rsp, fs:[{tcd}@tpoff+{ksp_offset}+8]",// use kernel stack pointer
+        "mov gs:[{tcd}@tpoff+{sp_offset}+8], rsp",// save stack pointer
+        "mov rsp, gs:[{tcd}@tpoff+{ksp_offset}+8]",// use kernel stack pointer
         asm::save_all_regs!(),
+        asm::load_kernel_fs!(),
+        "sti",// syscalls run with interrupts enabled
         "mov rdi, rsp",
         "call {c_syscall}",
+        "cli",// interrupts can't run with the user FS base
+        asm::load_user_fs!(),
         asm::load_all_regs!(),
-        "mov rsp, fs:[{tcd}@tpoff+{sp_offset}+8]",// use old stack pointer
+        "mov rsp, gs:[{tcd}@tpoff+{sp_offset}+8]",// use old stack pointer
         "swapgs",
         "sysretq"),
         tcd = sym TCD,
+        kernel_fs = sym KERNEL_FS_BASE,
         sp_offset = const offset_of!(ThreadControlData, user_stack_pointer),
         ksp_offset = const offset_of!(ThreadControlData, kernel_stack_pointer),
+        user_fs_offset = const offset_of!(ThreadControlData, user_fs_base),
         c_syscall = sym on_symcall_1,
         options(noreturn)
     )
@@ -1,7 +1,7 @@
 use x86_64::{VirtAddr, registers::rflags::RFlags};
 use memoffset::offset_of;
 
-use crate::{context::{current_task, elf::Elf, init::INIT_DIR}, syscalls::{TCD, ThreadControlData}, gdb_loop};
+use crate::{context::{current_task, elf::Elf, init::INIT_DIR}, syscalls::{TCD, ThreadControlData, asm::load_user_fs}, gdb_loop};
 
 use super::{SyscallError, SyscallResult};
 
@@ -12,21 +12,39 @@ pub fn check_addr_userspace(addr: usize) -> SyscallResult<()> {
     }
 }
 
+// Userspace lives in the lower canonical half
+const USERSPACE_END: u64 = 1 << 47;
 
-pub unsafe fn enter_userspace(ip: VirtAddr) -> ! {
+/// Address of userspace memory: check_addr_userspace only looks at the top bit, VirtAddr would
+/// sign-extend an address with bit 47 set into the kernel half (or panic if it's not canonical)
+pub fn user_virt_addr(addr: usize) -> SyscallResult<VirtAddr> {
+    if addr as u64 >= USERSPACE_END {
+        return Err(SyscallError::WrongParameters);
+    }
+    Ok(VirtAddr::new(addr as u64))
+}
+
+
+/// Jumps to ip in userspace, arg is passed in rdi
+pub unsafe fn enter_userspace(ip: VirtAddr, arg: u64) -> ! {
     let ip = ip.as_u64();
     let rflags = RFlags::INTERRUPT_FLAG.bits();
 
     core::arch::asm!(
-        "mov fs:[{tcd}@tpoff+{ksp_offset}+8], rsp",// save stack pointer
-        "mov rsp, fs:[{tcd}@tpoff+{sp_offset}+8]",// load user stack pointer
+        "cli",// interrupts can't run with the user FS base
+        "mov gs:[{tcd}@tpoff+{ksp_offset}+8], rsp",// save stack pointer
+        "mov rsp, gs:[{tcd}@tpoff+{sp_offset}+8]",// load user stack pointer
+        load_user_fs!(),
+        "mov rcx, rsi",
         "swapgs",
         "sysretq",
         tcd = sym TCD,
         ksp_offset = const offset_of!(ThreadControlData, kernel_stack_pointer),
         sp_offset = const offset_of!(ThreadControlData, user_stack_pointer),
-        in("rcx") ip,
+        user_fs_offset = const offset_of!(ThreadControlData, user_fs_base),
+        in("rsi") ip,// rcx is clobbered by load_user_fs
         in("r11") rflags,
+        in("rdi") arg,
         options(noreturn)
     )
 }
@@ -37,13 +55,15 @@ pub extern "C" fn start_initproc() {
             include_dir::InitDirEntry::File(x) => x,
             _ => panic!("initproc is not a file")
         };
-        let elf = Elf::new(init_proc).expect("Wrong elf in init data");
         let ctxp = current_task();
         let mut ctx = ctxp.write();
-        ctx.load_elf(&elf);
+        let load_base = ctx.page_table.lock().layout.load_base;
+        let elf = Elf::new(init_proc, load_base).expect("Wrong elf in init data");
+        ctx.prepare_initial_stack(&elf, None, &[b"initproc"], &[]).expect("Cannot prepare the initproc stack");
+        ctx.load_elf(&elf, None).expect("Cannot load initproc");
         unsafe { ctx.prepare_tcb(); }
         ctx.user_entry_point
     };
     //print_tables();
-    unsafe { enter_userspace(entry_point) };
+    unsafe { enter_userspace(entry_point, 0) };
 }
\ No newline at end of file
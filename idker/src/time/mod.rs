@@ -0,0 +1,258 @@
+use core::{cmp::{Ordering, Reverse}, fmt, sync::atomic::{AtomicU64, Ordering as AtomicOrdering}};
+
+use alloc::{collections::BinaryHeap, sync::{Arc, Weak}};
+use lazy_static::lazy_static;
+use spin::Mutex;
+use ::syscall::SyscallResult;
+
+use crate::{arch::{rtc, tsc}, context::wait_queue::WaitQueue, println};
+
+pub mod syscall;
+
+// Kernel timers: every armed timer has an entry in a heap ordered by deadline, expired entries
+// are popped from the timer interrupt (only when it interrupts userspace, the kernel might be
+// holding the locks we need) and by the idle loop.
+// A timer has at most one entry: re-arming, cancelling or dropping it removes the old one.
+// Entries carry the generation of the timer that created them, so an entry that has already
+// been popped when the timer is re-armed is ignored instead of firing the new deadline.
+
+lazy_static! {
+    static ref TIMERS: Mutex<BinaryHeap<Reverse<TimerEntry>>> = Mutex::new(BinaryHeap::new());
+}
+static NEXT_SEQUENCE: AtomicU64 = AtomicU64::new(0);
+// Realtime clock = monotonic clock + offset, the offset is computed at boot from the CMOS RTC
+static REALTIME_OFFSET_NS: AtomicU64 = AtomicU64::new(0);
+
+/// Reads the wall-clock time, the TSC must already be calibrated
+pub fn init() {
+    let unix_time = rtc::read_unix_time();
+    let offset = (unix_time * 1_000_000_000).saturating_sub(monotonic_ns());
+    REALTIME_OFFSET_NS.store(offset, AtomicOrdering::SeqCst);
+    println!("RTC: {} seconds since the epoch", unix_time);
+}
+
+/// Nanoseconds elapsed since the machine started (0 before the TSC is calibrated)
+pub fn monotonic_ns() -> u64 {
+    let per_ms = tsc::ticks_per_ms();
+    if per_ms == 0 {
+        return 0;
+    }
+    (tsc::rdtsc() as u128 * 1_000_000 / per_ms as u128) as u64
+}
+
+/// Nanoseconds since the unix epoch
+pub fn realtime_ns() -> u64 {
+    monotonic_ns() + boot_time_ns()
+}
+
+/// Realtime clock value when the monotonic clock was 0
+pub fn boot_time_ns() -> u64 {
+    REALTIME_OFFSET_NS.load(AtomicOrdering::SeqCst)
+}
+
+struct TimerEntry {
+    deadline: u64,
+    // Keeps timers with the same deadline in arming order
+    sequence: u64,
+    generation: u64,
+    // Dropped timers can't fire, their entry is removed by the drop
+    timer: Weak<Timer>,
+}
+
+impl PartialEq for TimerEntry {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for TimerEntry {}
+
+impl PartialOrd for TimerEntry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for TimerEntry {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.deadline, self.sequence).cmp(&(other.deadline, other.sequence))
+    }
+}
+
+#[derive(Default)]
+struct TimerState {
+    generation: u64,
+    deadline: Option<u64>,
+    fired: bool,
+}
+
+/// One-shot timer, tasks can block on it until its deadline passes
+pub struct Timer {
+    state: Mutex<TimerState>,
+    waiters: WaitQueue,
+    // Also woken when the timer fires, for tasks that wait for something else with a timeout
+    notify: Option<Weak<WaitQueue>>,
+}
+
+impl Timer {
+    pub fn new() -> Arc<Timer> {
+        Arc::new(Timer {
+            state: Mutex::new(TimerState::default()),
+            waiters: WaitQueue::new(),
+            notify: None,
+        })
+    }
+
+    /// Creates a timer that wakes up queue when it fires, its waiters should check has_fired
+    pub fn notifying(queue: &Arc<WaitQueue>) -> Arc<Timer> {
+        Arc::new(Timer {
+            state: Mutex::new(TimerState::default()),
+            waiters: WaitQueue::new(),
+            notify: Some(Arc::downgrade(queue)),
+        })
+    }
+
+    /// Arms the timer to fire at deadline (in monotonic ns), replacing any previous deadline
+    pub fn arm(self: &Arc<Self>, deadline: u64) -> SyscallResult<()> {
+        let mut timers = TIMERS.lock();
+        timers.try_reserve(1)?;
+        remove_entry(&mut timers, self);
+        let generation = {
+            let mut state = self.state.lock();
+            state.generation += 1;
+            state.deadline = Some(deadline);
+            state.fired = false;
+            state.generation
+        };
+        timers.push(Reverse(TimerEntry {
+            deadline,
+            sequence: NEXT_SEQUENCE.fetch_add(1, AtomicOrdering::Relaxed),
+            generation,
+            timer: Arc::downgrade(self),
+        }));
+        Ok(())
+    }
+
+    /// Disarms the timer, tasks waiting on it are woken up
+    pub fn cancel(&self) {
+        {
+            let mut timers = TIMERS.lock();
+            remove_entry(&mut timers, self);
+            let mut state = self.state.lock();
+            state.generation += 1;
+            state.deadline = None;
+        }
+        self.waiters.wake_all();
+    }
+
+    pub fn has_fired(&self) -> bool {
+        self.state.lock().fired
+    }
+
+    pub fn is_armed(&self) -> bool {
+        self.state.lock().deadline.is_some()
+    }
+
+    /// Blocks until the timer fires, returns false if it was (or gets) disarmed instead
+    pub fn wait(&self) -> SyscallResult<bool> {
+        self.waiters.wait_while(|| {
+            let state = self.state.lock();
+            !state.fired && state.deadline.is_some()
+        })?;
+        Ok(self.has_fired())
+    }
+
+    fn fire(&self, generation: u64) {
+        {
+            let mut state = self.state.lock();
+            if state.generation != generation || state.deadline.is_none() {
+                return;
+            }
+            state.deadline = None;
+            state.fired = true;
+        }
+        self.waiters.wake_all();
+        if let Some(queue) = self.notify.as_ref().and_then(Weak::upgrade) {
+            queue.wake_all();
+        }
+    }
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        // Only armed timers have an entry
+        if self.state.get_mut().deadline.is_some() {
+            remove_entry(&mut TIMERS.lock(), self);
+        }
+    }
+}
+
+fn remove_entry(timers: &mut BinaryHeap<Reverse<TimerEntry>>, timer: &Timer) {
+    timers.retain(|Reverse(x)| !core::ptr::eq(x.timer.as_ptr(), timer));
+}
+
+// Two timers are equal only if they are the same timer
+impl PartialEq for Timer {
+    fn eq(&self, other: &Self) -> bool {
+        core::ptr::eq(self, other)
+    }
+}
+
+impl Eq for Timer {}
+
+impl fmt::Debug for Timer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = self.state.lock();
+        f.debug_struct("Timer")
+            .field("deadline", &state.deadline)
+            .field("fired", &state.fired)
+            .finish()
+    }
+}
+
+/// Blocks the current task until deadline (in monotonic ns)
+pub fn sleep_until(deadline: u64) -> SyscallResult<()> {
+    if deadline <= monotonic_ns() {
+        return Ok(());
+    }
+    let timer = Timer::new();
+    timer.arm(deadline)?;
+    timer.wait()?;
+    Ok(())
+}
+
+/// Fires the expired timers.
+/// Must not be called while holding locks that a woken task (or another timer) might need
+pub fn process_timers() {
+    let now = monotonic_ns();
+    loop {
+        let entry = {
+            // Another cpu is already doing the job (or arming a timer), it's fine to skip a tick
+            let mut timers = match TIMERS.try_lock() {
+                Some(x) => x,
+                None => return,
+            };
+            match timers.peek() {
+                Some(Reverse(x)) if x.deadline <= now => {},
+                _ => return,
+            }
+            match timers.pop() {
+                Some(Reverse(x)) => x,
+                None => return,
+            }
+        };
+        if let Some(timer) = entry.timer.upgrade() {
+            timer.fire(entry.generation);
+        }
+    }
+}
+
+#[test_case]
+fn timer_entries_ordered_by_deadline_then_arming() {
+    let entry = |deadline, sequence, generation| TimerEntry { deadline, sequence, generation, timer: Weak::new() };
+    assert!(entry(1, 7, 0) < entry(2, 0, 0));
+    assert!(entry(5, 1, 0) < entry(5, 2, 0));
+    // The generation only tells if the entry is stale
+    assert!(entry(5, 1, 0) == entry(5, 1, 3));
+    assert!(Reverse(entry(1, 0, 0)) > Reverse(entry(2, 0, 0)));
+}
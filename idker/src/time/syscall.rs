@@ -0,0 +1,77 @@
+use core::convert::TryFrom;
+
+use alloc::sync::Arc;
+use syscall::{ClockId, SyscallError, SyscallResult, TimerWaitMode};
+
+use crate::{capability::{Capability, CapabilityPerms, CapabilityType, syscall::CapabilityHandle}, context::current_task};
+
+use super::{Timer, monotonic_ns, realtime_ns, sleep_until};
+
+
+fn get_timer(handle: CapabilityHandle) -> SyscallResult<Arc<Timer>> {
+    let task_lock = current_task();
+    let task = task_lock.read();
+    let caps = task.capabilities.lock();
+    match &caps.get(handle).ok_or(SyscallError::WrongCapability)?.ctype {
+        CapabilityType::Timer(x) => Ok(x.clone()),
+        _ => Err(SyscallError::WrongCapability),
+    }
+}
+
+pub fn sleep(duration: usize) -> SyscallResult<()> {
+    sleep_until(monotonic_ns().saturating_add(duration as u64))
+}
+
+pub fn sleep_until_deadline(deadline: usize) -> SyscallResult<()> {
+    sleep_until(deadline as u64)
+}
+
+pub fn create() -> SyscallResult<CapabilityHandle> {
+    let timer = Timer::new();
+    let perms = CapabilityPerms::DUPLICATE | CapabilityPerms::SHAREABLE | CapabilityPerms::TRANSFER;
+    let task_lock = current_task();
+    let task = task_lock.read();
+    let mut caps = task.capabilities.lock();
+    caps.insert(Capability { perms, ctype: CapabilityType::Timer(timer) })
+}
+
+pub fn arm(handle: CapabilityHandle, time: usize, relative: usize) -> SyscallResult<()> {
+    let timer = get_timer(handle)?;
+    let deadline = match relative {
+        0 => time as u64,
+        1 => monotonic_ns().saturating_add(time as u64),
+        _ => return Err(SyscallError::WrongParameters),
+    };
+    timer.arm(deadline)
+}
+
+pub fn cancel(handle: CapabilityHandle) -> SyscallResult<()> {
+    get_timer(handle)?.cancel();
+    Ok(())
+}
+
+pub fn wait(handle: CapabilityHandle, mode: usize) -> SyscallResult<()> {
+    let mode = TimerWaitMode::from_bits(mode as u8).ok_or(SyscallError::WrongParameters)?;
+    let timer = get_timer(handle)?;
+
+    if mode.contains(TimerWaitMode::NON_BLOCKING) {
+        return match (timer.has_fired(), timer.is_armed()) {
+            (true, _) => Ok(()),
+            (false, true) => Err(SyscallError::TimerPending),
+            (false, false) => Err(SyscallError::TimerDisarmed),
+        };
+    }
+
+    if timer.wait()? {
+        Ok(())
+    } else {
+        Err(SyscallError::TimerDisarmed)
+    }
+}
+
+pub fn clock_get_time(clock: usize) -> SyscallResult<u64> {
+    match ClockId::try_from(clock as u64).map_err(|_| SyscallError::WrongParameters)? {
+        ClockId::Monotonic => Ok(monotonic_ns()),
+        ClockId::Realtime => Ok(realtime_ns()),
+    }
+}
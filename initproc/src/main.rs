@@ -14,7 +14,7 @@ pub extern "C" fn _start() -> ! {
     // Lol, right, we don't have println
     //println!("My pid: {}", s::Process::my_pid());
     // Exit
-    s::exit();
+    s::exit(0);
 }
 
 #[lang = "eh_personality"] extern fn eh_personality() {}
@@ -1,5 +1,5 @@
 
-use core::{str::Utf8Error};
+use core::{convert::TryFrom, str::Utf8Error};
 
 use num_enum::TryFromPrimitive;
 use bitflags::bitflags;
@@ -7,7 +7,7 @@ use bitflags::bitflags;
 #[derive(Clone, Copy, TryFromPrimitive, Debug, PartialEq, Eq)]
 #[repr(u64)]
 pub enum SyscallCode {
-    Exit = 0,
+    Exit = 0,// Terminates the current process, args: exit code
     Yield,
 
     CapabilityClone = 0x100,// Clones capability
@@ -26,24 +26,79 @@ pub enum SyscallCode {
     FsHandleWrite,// args: handle, &mut [u8]
     FsHandleClose,
 
-    // TODO: implement conversations
-    ConversationCreateP2p = 0x300,// Creates a p2p conversation (requires capability)
+    ConversationCreateP2p = 0x300,// Creates a p2p conversation (requires capability), returns the two endpoints
     ConversationCreatePublic,// Creates a non-p2p conversation (requires capability)
-    ConversationTalk,// Sends a binary message to the conversation
-    ConversationCapShare,// Shares a capability to the conversation
-    ConversationCapTransfer,// Transfers a capability to a p2p conversation
+    ConversationTalk,// Sends a binary message to the conversation, args: handle, &[u8]
+    ConversationCapShare,// Like talk but also shares capabilities, args: handle, &[u8], &[capability handle]
+    ConversationCapTransfer,// Like talk but transfers capabilities (only in p2p conversations), same args as share
+    // Receives a message, args: handle, &mut [u8], &mut [capability handle], mode: ConversationListenMode
+    // returns (length, sender pid, received capabilities count), received capabilities are added to the listener
+    ConversationListen,
 
     ProcessMyPid = 0x400,// Returns current process pid
     ProcessSpawn,// Spawns a new empty process (requires capability)
     ProcessCapShare,// Share a capability with a child process (needs to be empty)
     ProcessCapTransfer,// Transfer a capability to a child process (needs to be empty)
-    ProcessExec,// Starts a program in an empty process, maintaining its capabilities
+    // Starts a program in an empty child process, maintaining its capabilities
+    // args: pid, fd of the program, argv: *const ExecArg, argc, envp: *const ExecArg, envc
+    // The program starts with argc, argv, envp and the auxiliary vector (AT_*) on the stack
+    // #! scripts run their interpreter with the path the script was opened with in place of argv[0]
+    // (FsNotExecutable if the descriptor doesn't know it)
+    ProcessExec,
+    // Waits for a child to exit and reaps it, args: pid (0 for any child), mode: ProcessWaitMode
+    // returns (pid, ExitReason as raw values)
+    ProcessWait,
+    ProcessSetSubreaper,// Adopts orphaned descendants instead of initproc, args: enabled (bool)
+    // Transfers a p2p conversation endpoint to the kernel, it will be used to report the faults of a process
+    // args: pid (0 for the current process, or a child), endpoint handle
+    // The kernel sends a FaultMessage on the endpoint and waits for a FaultReply (see FaultAction),
+    // replies can't carry capabilities and the other side must not be held by the process itself
+    ProcessFaultPortSet,
+    ProcessFaultPortClear,// Faults will kill the process again, args: pid (0 for the current process, or a child)
+    // Restricts the cpus where a process can run, args: pid (0 for the current process, or a child),
+    // mask (bit i = logical cpu i)
+    ProcessSetAffinity,
+    // Changes the scheduling class of a process, args: pid (0 for the current process, or a child),
+    // class, level (see Priority::into_raw), it applies to every thread of the process,
+    // raising the priority requires a capability
+    ProcessSetPriority,
+    // Spawns a child running the program at a path, with the given capabilities and files, all at once:
+    // the child runs fully configured or isn't created at all (requires capability)
+    // args: *const SpawnExecArgs, returns the pid of the child
+    // The child finds its capabilities at handles 0.. (in order) and its files at the same descriptors
+    ProcessSpawnExec,
 
-    MemoryMapVirt = 0x500,// Maps virtual memory to RAM (requires capability) params: vfrom-vlen, perms
+    // Maps virtual memory to RAM (requires capability) params: vfrom-vlen, perms
+    // With vfrom 0 the kernel picks the address (starting from a random base), returns vfrom
+    MemoryMapVirt = 0x500,
     //MemoryMapFile?
     MemoryMapPhys,// Maps virtual memoty to physical (requires capability) params: vrom-vlen tfrom, perms
     MemoryEditPerms,// Change permissions of page ranges
-    MemoryUnmap,// Unmaps previously mapped memory vfrom-vlen
+    // Unmaps previously mapped memory params: vfrom-vlen (pages that aren't mapped are skipped)
+    // Memory allocated by MemoryMapVirt (or loaded by exec) is freed
+    MemoryUnmap,
+
+    Sleep = 0x600,// Blocks the current process, args: duration (ns)
+    SleepUntil,// Blocks the current process until a deadline, args: monotonic time (ns)
+    TimerCreate,// Creates a disarmed one-shot timer, returns its capability handle
+    TimerArm,// (Re)arms a timer, args: handle, time (ns), relative (bool, if false time is a monotonic deadline)
+    TimerCancel,// Disarms a timer, args: handle
+    TimerWait,// Waits for a timer to fire, args: handle, mode: TimerWaitMode
+    ClockGetTime,// Reads a clock, args: clock: ClockId, returns the time in ns
+
+    // Starts a thread in the current process, sharing its memory, capabilities and files
+    // args: entry, stack pointer, arg (passed to entry in rdi), returns the thread id
+    ThreadSpawn = 0x700,
+    ThreadExit,// Terminates the current thread, the process exits with its last thread, args: exit code
+    ThreadMyTid,// Returns the id of the current thread (the first thread has the id of the process)
+    ThreadSetTlsBase,// Sets the FS or GS base of the current thread, args: register: TlsRegister, address
+
+    // Sleeps until woken by FutexWake if the u32 at addr still holds expected (checked atomically
+    // with going to sleep), args: addr (4-byte aligned), expected, timeout (ns, 0 waits forever)
+    // Futexes are identified by physical address, so they work across shared memory
+    // A woken task returns Ok even if the word has changed back to expected in the meantime
+    FutexWait = 0x800,
+    FutexWake,// Wakes up to count tasks waiting on addr, args: addr, count, returns how many were woken
 }
 
 
@@ -64,6 +119,25 @@ pub enum SyscallError {
     WrongCapabilityPerms,
     WrongProcess,
     MemoryAlreadyMapped,
+    ConversationEmpty,// Non-blocking listen found no message
+    ConversationClosed,// The other side of a p2p conversation is gone
+    ConversationFull,// Too many messages are waiting to be read
+    ConversationBufferTooSmall,// The next message does not fit in the buffer (it's left in the conversation)
+    ProcessRunning,// Non-blocking wait found no exited child
+    TimerPending,// Non-blocking wait found an armed timer that hasn't fired yet
+    TimerDisarmed,// The timer isn't armed (or has been cancelled while waiting)
+    Interrupted,// The process is exiting, the thread exits instead of returning it
+    MemoryNotMapped,// The address doesn't point to mapped memory
+    FutexValueChanged,// The futex didn't hold the expected value, nothing to wait for
+    TimedOut,// The timeout expired before the event happened
+    ArgumentsTooLong,// The exec arguments and environment don't fit in the initial stack
+    ExecMalformed,// The program is a broken ELF (truncated, inconsistent sizes, misaligned segments...)
+    ExecSegmentsOverlap,// Two loadable segments of the program share a page
+    ExecAddressNotUserspace,// The program would be loaded (or start) outside of userspace
+    ExecRelocationUnsupported,// The program needs relocations the kernel can't apply (only R_X86_64_RELATIVE is)
+    ExecInterpreterLoop,// Too many #! scripts run each other
+    ConversationCapabilitiesRefused,// The other side doesn't accept capabilities (ex. it's a fault port)
+    FaultPortSelfServed,// The process holds the other side of the fault port, nobody could answer its faults
     UnknownError = u64::MAX,
 }
 
@@ -91,4 +165,338 @@ bitflags! {
         const WRITE = 0x2;
         //const APPEND = 0x4; ?
     }
-}
\ No newline at end of file
+}
+
+// Levels of the real-time priority class (0..PRIORITY_REALTIME_LEVELS)
+pub const PRIORITY_REALTIME_LEVELS: u8 = 32;
+
+/// Scheduling class of a process
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum Priority {
+    RealTime(u8),// Runs before any other class, higher levels first
+    #[default]
+    Normal,// Time sharing between the normal processes
+    Idle,// Runs only when nothing else can
+}
+
+impl Priority {
+    pub fn into_raw(self) -> (u64, u64) {
+        match self {
+            Priority::RealTime(level) => (0, level as u64),
+            Priority::Normal => (1, 0),
+            Priority::Idle => (2, 0),
+        }
+    }
+
+    pub fn from_raw(class: u64, level: u64) -> Option<Priority> {
+        match (class, level) {
+            (0, x) if x < PRIORITY_REALTIME_LEVELS as u64 => Some(Priority::RealTime(x as u8)),
+            (1, 0) => Some(Priority::Normal),
+            (2, 0) => Some(Priority::Idle),
+            _ => None,
+        }
+    }
+
+    fn rank(&self) -> u32 {
+        match self {
+            Priority::Idle => 0,
+            Priority::Normal => 1,
+            Priority::RealTime(level) => 2 + *level as u32,
+        }
+    }
+}
+
+// More important is greater
+impl PartialOrd for Priority {
+    fn partial_cmp(&self, other: &Self) -> Option<core::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Priority {
+    fn cmp(&self, other: &Self) -> core::cmp::Ordering {
+        self.rank().cmp(&other.rank())
+    }
+}
+
+// Address of the kernel data page, mapped read-only in every process
+pub const KERNEL_DATA_PAGE_ADDR: u64 = 0x3FFF_F000;
+
+/// Contents of the kernel data page, they can be read without a syscall (the kernel may update
+/// them at any moment, use volatile reads)
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+pub struct KernelDataPage {
+    pub pid: u64,
+    pub parent_pid: u64,// 0 if the process has no parent
+    pub boot_time_ns: u64,// Realtime clock value when the monotonic clock was 0
+    pub tsc_ticks_per_ms: u64,// Monotonic ns = tsc * 1_000_000 / tsc_ticks_per_ms (0 if unknown)
+    pub cpu_count: u64,
+}
+
+#[derive(Clone, Copy, TryFromPrimitive, Debug, PartialEq, Eq)]
+#[repr(u64)]
+pub enum ClockId {
+    Monotonic = 0,// Time since boot, never goes back
+    Realtime,// Time since the unix epoch, read from the RTC at boot
+}
+
+#[derive(Clone, Copy, TryFromPrimitive, Debug, PartialEq, Eq)]
+#[repr(u64)]
+pub enum TlsRegister {
+    Fs = 0,// Thread locals of x86_64 programs, set up by exec from the PT_TLS segment
+    Gs,// Free for userspace to use
+}
+
+bitflags! {
+    pub struct TimerWaitMode: u8 {
+        const NON_BLOCKING = 0x1;
+    }
+}
+
+bitflags! {
+    pub struct ConversationListenMode: u8 {
+        const NON_BLOCKING = 0x1;
+    }
+}
+
+bitflags! {
+    pub struct ProcessWaitMode: u8 {
+        const NON_BLOCKING = 0x1;
+    }
+}
+
+#[derive(Clone, Copy, TryFromPrimitive, Debug, PartialEq, Eq)]
+#[repr(u64)]
+pub enum FaultKind {
+    PageFault = 1,
+    GeneralProtection,
+    InvalidOpcode,
+    DivideError,
+    Breakpoint,
+    X87FloatingPoint,// Unmasked x87 exception
+    SimdFloatingPoint,// Unmasked SSE/AVX exception (see MXCSR)
+}
+
+impl FaultKind {
+    // Interrupt vector of the exception
+    pub fn vector(self) -> u64 {
+        match self {
+            FaultKind::DivideError => 0,
+            FaultKind::Breakpoint => 3,
+            FaultKind::InvalidOpcode => 6,
+            FaultKind::GeneralProtection => 13,
+            FaultKind::PageFault => 14,
+            FaultKind::X87FloatingPoint => 16,
+            FaultKind::SimdFloatingPoint => 19,
+        }
+    }
+
+    pub fn from_vector(vector: u64) -> Option<FaultKind> {
+        Some(match vector {
+            0 => FaultKind::DivideError,
+            3 => FaultKind::Breakpoint,
+            6 => FaultKind::InvalidOpcode,
+            13 => FaultKind::GeneralProtection,
+            14 => FaultKind::PageFault,
+            16 => FaultKind::X87FloatingPoint,
+            19 => FaultKind::SimdFloatingPoint,
+            _ => return None,
+        })
+    }
+}
+
+// How a process ended
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExitReason {
+    Exited(u64),// Called Exit with this code
+    Faulted { kind: FaultKind, address: u64, rip: u64 },// Killed by a fault in user mode
+}
+
+impl ExitReason {
+    // Encoded as (tag, a, b), tag is 0 for Exited(a) or the FaultKind (address = a, rip = b)
+    pub fn into_raw(self) -> (u64, u64, u64) {
+        match self {
+            ExitReason::Exited(code) => (0, code, 0),
+            ExitReason::Faulted { kind, address, rip } => (kind as u64, address, rip),
+        }
+    }
+
+    pub fn from_raw(tag: u64, a: u64, b: u64) -> Option<ExitReason> {
+        Some(match tag {
+            0 => ExitReason::Exited(a),
+            x => ExitReason::Faulted {
+                kind: FaultKind::try_from(x).ok()?,
+                address: a,
+                rip: b,
+            },
+        })
+    }
+}
+
+// User registers of a faulting process
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct FaultRegisters {
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rbp: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    pub rip: u64,
+    pub rsp: u64,
+    pub rflags: u64,
+}
+
+// Sent by the kernel to the fault port when a process faults, the process waits for a FaultReply
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(C)]
+pub struct FaultMessage {
+    pub pid: u64,
+    pub vector: u64,// See FaultKind::from_vector
+    pub error_code: u64,
+    pub cr2: u64,// Accessed address (page faults only)
+    pub regs: FaultRegisters,
+}
+
+#[derive(Clone, Copy, TryFromPrimitive, Debug, PartialEq, Eq)]
+#[repr(u64)]
+pub enum FaultAction {
+    Resume = 0,// Retries the faulting instruction (ex. after mapping the missing page)
+    SetRegisters,// Resumes with the registers in the reply (only arithmetic flags can be changed in rflags)
+    Kill,// Kills the process as if it had no fault port
+}
+
+// Sent by the fault port to answer a FaultMessage
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(C)]
+pub struct FaultReply {
+    pub action: u64,// FaultAction
+    pub regs: FaultRegisters,
+}
+
+// Fault messages travel as plain bytes in conversations
+macro_rules! impl_raw_message {
+    ($name:ident) => {
+        impl $name {
+            pub fn as_bytes(&self) -> &[u8] {
+                // Safety: the struct is repr(C) and only made of integers
+                unsafe { core::slice::from_raw_parts(self as *const _ as *const u8, core::mem::size_of::<Self>()) }
+            }
+
+            pub fn from_bytes(data: &[u8]) -> Option<Self> {
+                if data.len() != core::mem::size_of::<Self>() {
+                    return None;
+                }
+                // Safety: every bit pattern is valid
+                Some(unsafe { core::ptr::read_unaligned(data.as_ptr() as *const Self) })
+            }
+        }
+    };
+}
+
+impl_raw_message!(FaultMessage);
+impl_raw_message!(FaultReply);
+
+// Biggest message that can be sent in a single ConversationTalk
+pub const CONVERSATION_MAX_MESSAGE_SIZE: usize = 4096;
+// Maximum number of capabilities carried by a single message
+pub const CONVERSATION_MAX_CAPABILITIES: usize = 16;
+// How many messages can wait in a conversation queue before ConversationFull is returned
+pub const CONVERSATION_MAX_QUEUED: usize = 64;
+
+/// A string passed to ProcessExec (an argument or an environment variable, usually "KEY=value")
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+pub struct ExecArg {
+    pub ptr: u64,
+    pub len: u64,
+}
+
+// Maximum number of arguments (and, separately, of environment variables) accepted by ProcessExec
+pub const EXEC_MAX_ARGS: usize = 128;
+// Space reserved at the top of the initial stack for the strings and the pointers to them
+pub const EXEC_MAX_ARGS_SIZE: usize = 16 * 1024;
+
+// Auxiliary vector entries, found after envp on the initial stack as (type, value) pairs
+pub const AT_NULL: u64 = 0;// End of the vector
+pub const AT_PHDR: u64 = 3;// Address of the program headers
+pub const AT_PHENT: u64 = 4;// Size of a program header
+pub const AT_PHNUM: u64 = 5;// Number of program headers
+pub const AT_PAGESZ: u64 = 6;
+pub const AT_BASE: u64 = 7;// Where the interpreter (PT_INTERP) is loaded
+pub const AT_ENTRY: u64 = 9;// Entry point of the program
+pub const AT_RANDOM: u64 = 25;// Address of 16 random bytes
+
+/// How ProcessSpawnExec gives a capability to the child
+#[derive(Clone, Copy, TryFromPrimitive, Debug, PartialEq, Eq)]
+#[repr(u64)]
+pub enum CapabilityPassMode {
+    Share = 0,// The capability must be SHAREABLE, both processes keep it
+    Transfer,// The capability must be TRANSFER, it's removed from the parent
+}
+
+/// A capability passed to the child of ProcessSpawnExec
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+pub struct SpawnCapability {
+    pub handle: u64,
+    pub mode: u64,// CapabilityPassMode
+}
+
+/// Arguments of ProcessSpawnExec, the strings and arrays are in the memory of the caller
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+pub struct SpawnExecArgs {
+    pub path: ExecArg,
+    pub argv: u64,// *const ExecArg
+    pub argc: u64,
+    pub envp: u64,// *const ExecArg
+    pub envc: u64,
+    pub capabilities: u64,// *const SpawnCapability
+    pub capabilities_len: u64,
+    pub files: u64,// *const u64, descriptors of the files that the child inherits
+    pub files_len: u64,
+}
+
+// Maximum number of capabilities (and, separately, of files) passed by ProcessSpawnExec
+pub const SPAWN_MAX_CAPABILITIES: usize = 32;
+pub const SPAWN_MAX_FILES: usize = 32;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn priority_ordering() {
+        assert!(Priority::Idle < Priority::Normal);
+        assert!(Priority::Normal < Priority::RealTime(0));
+        assert!(Priority::RealTime(0) < Priority::RealTime(1));
+        assert!(Priority::RealTime(PRIORITY_REALTIME_LEVELS - 1) > Priority::RealTime(0));
+        assert_eq!(Priority::default(), Priority::Normal);
+    }
+
+    #[test]
+    fn priority_raw_round_trip() {
+        let all = (0..PRIORITY_REALTIME_LEVELS).map(Priority::RealTime)
+            .chain([Priority::Normal, Priority::Idle]);
+        for x in all {
+            let (class, level) = x.into_raw();
+            assert_eq!(Priority::from_raw(class, level), Some(x));
+        }
+        assert_eq!(Priority::from_raw(0, PRIORITY_REALTIME_LEVELS as u64), None);
+        assert_eq!(Priority::from_raw(1, 1), None);
+        assert_eq!(Priority::from_raw(2, 1), None);
+        assert_eq!(Priority::from_raw(3, 0), None);
+    }
+}
@@ -6,6 +6,6 @@ pub mod raw;
 #[cfg(feature = "user")]
 pub mod syscall;
 
-pub use common::{SyscallCode, SyscallError, SyscallResult, FsOpenMode};
+pub use common::{SyscallCode, SyscallError, SyscallResult, FsOpenMode, ConversationListenMode, ProcessWaitMode, TimerWaitMode, ClockId, TlsRegister, FaultKind, ExitReason, FaultRegisters, FaultMessage, FaultAction, FaultReply, CONVERSATION_MAX_MESSAGE_SIZE, CONVERSATION_MAX_CAPABILITIES, CONVERSATION_MAX_QUEUED, KernelDataPage, KERNEL_DATA_PAGE_ADDR, Priority, PRIORITY_REALTIME_LEVELS, ExecArg, EXEC_MAX_ARGS, EXEC_MAX_ARGS_SIZE, AT_NULL, AT_PHDR, AT_PHENT, AT_PHNUM, AT_PAGESZ, AT_BASE, AT_ENTRY, AT_RANDOM, CapabilityPassMode, SpawnCapability, SpawnExecArgs, SPAWN_MAX_CAPABILITIES, SPAWN_MAX_FILES};
 
 
@@ -4,7 +4,7 @@ use crate::SyscallError;
 use crate::SyscallResult;
 
 
-create_syscall!(raw_exit, Exit, 0, 0);
+create_syscall!(raw_exit, Exit, 1, 0);
 create_syscall!(raw_yield, Yield, 0, 0);
 
 // FileSystem
@@ -20,14 +20,48 @@ create_syscall!(raw_capability_inspect, CapabilityInspect, 2, 2);
 create_syscall!(raw_capability_restrict, CapabilityRestrict, 3, 0);
 create_syscall!(raw_capability_drop, CapabilityDrop, 1, 0);
 
+// Conversation
+create_syscall!(raw_conversation_create_p2p, ConversationCreateP2p, 0, 2);
+create_syscall!(raw_conversation_create_public, ConversationCreatePublic, 0, 1);
+create_syscall!(raw_conversation_talk, ConversationTalk, 3, 0);
+create_syscall!(raw_conversation_cap_share, ConversationCapShare, 5, 0);
+create_syscall!(raw_conversation_cap_transfer, ConversationCapTransfer, 5, 0);
+create_syscall!(raw_conversation_listen, ConversationListen, 6, 3);
+
 // Process
 create_syscall!(raw_process_my_pid, ProcessMyPid, 0, 1);
 create_syscall!(raw_process_spawn, ProcessSpawn, 0, 1);
 create_syscall!(raw_process_cap_share, ProcessCapShare, 2, 0);
 create_syscall!(raw_process_cap_transfer, ProcessCapTransfer, 2, 0);
-create_syscall!(raw_process_exec, ProcessExec, 2, 0);
+create_syscall!(raw_process_exec, ProcessExec, 6, 0);
+create_syscall!(raw_process_wait, ProcessWait, 2, 4);
+create_syscall!(raw_process_set_subreaper, ProcessSetSubreaper, 1, 0);
+create_syscall!(raw_process_fault_port_set, ProcessFaultPortSet, 2, 0);
+create_syscall!(raw_process_fault_port_clear, ProcessFaultPortClear, 1, 0);
+create_syscall!(raw_process_set_affinity, ProcessSetAffinity, 2, 0);
+create_syscall!(raw_process_set_priority, ProcessSetPriority, 3, 0);
+create_syscall!(raw_process_spawn_exec, ProcessSpawnExec, 1, 1);
 
 // Virt Mem
-create_syscall!(raw_memory_map_virt, MemoryMapVirt, 3, 0);
+create_syscall!(raw_memory_map_virt, MemoryMapVirt, 3, 1);
 create_syscall!(raw_memory_map_phys, MemoryMapPhys, 4, 0);
-//create_syscall!(raw_memory_unmap, MemoryUnmap, 4, 0); TODO
+create_syscall!(raw_memory_unmap, MemoryUnmap, 2, 0);
+
+// Time
+create_syscall!(raw_sleep, Sleep, 1, 0);
+create_syscall!(raw_sleep_until, SleepUntil, 1, 0);
+create_syscall!(raw_timer_create, TimerCreate, 0, 1);
+create_syscall!(raw_timer_arm, TimerArm, 3, 0);
+create_syscall!(raw_timer_cancel, TimerCancel, 1, 0);
+create_syscall!(raw_timer_wait, TimerWait, 2, 0);
+create_syscall!(raw_clock_get_time, ClockGetTime, 1, 1);
+
+// Thread
+create_syscall!(raw_thread_spawn, ThreadSpawn, 3, 1);
+create_syscall!(raw_thread_exit, ThreadExit, 1, 0);
+create_syscall!(raw_thread_my_tid, ThreadMyTid, 0, 1);
+create_syscall!(raw_thread_set_tls_base, ThreadSetTlsBase, 2, 0);
+
+// Futex
+create_syscall!(raw_futex_wait, FutexWait, 3, 0);
+create_syscall!(raw_futex_wake, FutexWake, 2, 1);
@@ -1,10 +1,10 @@
-use core::num::NonZeroU64;
+use core::{num::NonZeroU64, sync::atomic::AtomicU32};
 
-use crate::{raw::*, SyscallResult, FsOpenMode, SyscallError};
+use crate::{raw::*, SyscallResult, FsOpenMode, SyscallError, ConversationListenMode, ProcessWaitMode, TimerWaitMode, ClockId, TlsRegister, ExitReason, KernelDataPage, KERNEL_DATA_PAGE_ADDR, Priority, ExecArg, EXEC_MAX_ARGS, CapabilityPassMode, SpawnCapability, SpawnExecArgs, SPAWN_MAX_CAPABILITIES, SPAWN_MAX_FILES};
 
 
-pub fn exit() -> ! {
-    unsafe { raw_exit() }.unwrap();
+pub fn exit(code: u64) -> ! {
+    unsafe { raw_exit(code) }.unwrap();
     loop {}
 }
 
@@ -12,6 +12,35 @@ pub fn yield_() -> () {
     unsafe { raw_yield() }.unwrap();
 }
 
+/// Reads the kernel data page of the current process (no syscall needed)
+pub fn kernel_data() -> KernelDataPage {
+    // The kernel maps it in every process
+    unsafe { core::ptr::read_volatile(KERNEL_DATA_PAGE_ADDR as *const KernelDataPage) }
+}
+
+/// Blocks the current process for (at least) duration nanoseconds
+pub fn sleep(duration: u64) -> SyscallResult<()> {
+    unsafe { raw_sleep(duration) }
+}
+
+/// Blocks the current process until the monotonic clock reaches deadline (in nanoseconds)
+pub fn sleep_until(deadline: u64) -> SyscallResult<()> {
+    unsafe { raw_sleep_until(deadline) }
+}
+
+/// Sleeps until futex_wake is called on word, if it still holds expected.
+/// Fails with FutexValueChanged if it doesn't, or with TimedOut after timeout nanoseconds
+pub fn futex_wait(word: &AtomicU32, expected: u32, timeout: Option<u64>) -> SyscallResult<()> {
+    // 0 means no timeout for the kernel
+    let timeout = timeout.map_or(0, |x| x.max(1));
+    unsafe { raw_futex_wait(word.as_ptr() as u64, expected as u64, timeout) }
+}
+
+/// Wakes up to count tasks sleeping on word, returns how many were woken up
+pub fn futex_wake(word: &AtomicU32, count: u64) -> SyscallResult<u64> {
+    unsafe { raw_futex_wake(word.as_ptr() as u64, count) }
+}
+
 
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct File(NonZeroU64);
@@ -39,11 +68,126 @@ impl Drop for File {
     }
 }
 
+/// One side of a conversation, backed by a capability
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Conversation(u64);
+
+impl Conversation {
+    pub fn create_p2p() -> SyscallResult<(Conversation, Conversation)> {
+        let (a, b) = unsafe { raw_conversation_create_p2p() }?;
+        Ok((Conversation(a), Conversation(b)))
+    }
+
+    pub fn create_public() -> SyscallResult<Conversation> {
+        let handle = unsafe { raw_conversation_create_public() }?;
+        Ok(Conversation(handle))
+    }
+
+    /// Wraps a conversation capability obtained in other ways (ex. shared by the parent process)
+    pub fn from_capability(cap_id: u64) -> Conversation {
+        Conversation(cap_id)
+    }
+
+    pub fn capability(&self) -> u64 {
+        self.0
+    }
+
+    /// Gives up the ownership of the capability without dropping it (ex. after transferring it)
+    pub fn into_capability(self) -> u64 {
+        let cap_id = self.0;
+        core::mem::forget(self);
+        cap_id
+    }
+
+    pub fn talk(&self, data: &[u8]) -> SyscallResult<()> {
+        unsafe { raw_conversation_talk(self.0, data.as_ptr() as u64, data.len() as u64) }
+    }
+
+    /// Like talk but also shares the capabilities (they need the SHAREABLE permission)
+    pub fn talk_share(&self, data: &[u8], caps: &[u64]) -> SyscallResult<()> {
+        unsafe {
+            raw_conversation_cap_share(self.0, data.as_ptr() as u64, data.len() as u64, caps.as_ptr() as u64, caps.len() as u64)
+        }
+    }
+
+    /// Like talk but also transfers the capabilities (they need the TRANSFER permission),
+    /// only p2p conversations can transfer capabilities.
+    /// On success the capabilities are no longer ours, remember to forget their wrappers
+    pub fn talk_transfer(&self, data: &[u8], caps: &[u64]) -> SyscallResult<()> {
+        unsafe {
+            raw_conversation_cap_transfer(self.0, data.as_ptr() as u64, data.len() as u64, caps.as_ptr() as u64, caps.len() as u64)
+        }
+    }
+
+    /// Waits for a message, returns the message and the pid of its sender.
+    /// Messages carrying capabilities cannot be heard this way, use listen_capabilities
+    pub fn listen<'a>(&self, buf: &'a mut [u8]) -> SyscallResult<(&'a mut [u8], u64)> {
+        let msg = self.listen_mode(buf, &mut [], ConversationListenMode::empty())?;
+        Ok((msg.data, msg.sender))
+    }
+
+    /// Like listen but fails with ConversationEmpty instead of waiting
+    pub fn try_listen<'a>(&self, buf: &'a mut [u8]) -> SyscallResult<(&'a mut [u8], u64)> {
+        let msg = self.listen_mode(buf, &mut [], ConversationListenMode::NON_BLOCKING)?;
+        Ok((msg.data, msg.sender))
+    }
+
+    /// Waits for a message that can also carry capabilities, their handles are written in caps
+    pub fn listen_capabilities<'a>(&self, buf: &'a mut [u8], caps: &'a mut [u64]) -> SyscallResult<ConversationMessage<'a>> {
+        self.listen_mode(buf, caps, ConversationListenMode::empty())
+    }
+
+    /// Like listen_capabilities but fails with ConversationEmpty instead of waiting
+    pub fn try_listen_capabilities<'a>(&self, buf: &'a mut [u8], caps: &'a mut [u64]) -> SyscallResult<ConversationMessage<'a>> {
+        self.listen_mode(buf, caps, ConversationListenMode::NON_BLOCKING)
+    }
+
+    fn listen_mode<'a>(&self, buf: &'a mut [u8], caps: &'a mut [u64], mode: ConversationListenMode) -> SyscallResult<ConversationMessage<'a>> {
+        let (len, sender, caps_len) = unsafe {
+            raw_conversation_listen(
+                self.0,
+                buf.as_ptr() as u64, buf.len() as u64,
+                caps.as_ptr() as u64, caps.len() as u64,
+                mode.bits() as u64
+            )
+        }?;
+        Ok(ConversationMessage {
+            data: &mut buf[..len as usize],
+            capabilities: &mut caps[..caps_len as usize],
+            sender,
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct ConversationMessage<'a> {
+    pub data: &'a mut [u8],
+    // Handles of the received capabilities, they are now owned by the current process
+    pub capabilities: &'a mut [u64],
+    pub sender: u64,
+}
+
+impl Drop for Conversation {
+    fn drop(&mut self) {
+        unsafe { raw_capability_drop(self.0) }.expect("Cannot drop Conversation capability")
+    }
+}
+
 pub struct Process(NonZeroU64);
 
 impl Process {
     pub fn my_pid() -> SyscallResult<u64> {
-        unsafe { raw_process_my_pid() }
+        Ok(kernel_data().pid)
+    }
+
+    /// Pid of the parent of the current process (0 if it has none)
+    pub fn my_parent_pid() -> u64 {
+        kernel_data().parent_pid
+    }
+
+    /// Number of cpus that run processes
+    pub fn cpu_count() -> u64 {
+        kernel_data().cpu_count
     }
 
     pub fn spawn() -> SyscallResult<Self> {
@@ -60,7 +204,342 @@ impl Process {
         unsafe { raw_process_cap_transfer(self.0.get(), cap_id) }
     }
 
-    pub fn exec(path: &str) -> SyscallResult<()> {
-        unsafe { raw_process_exec(path.as_ptr() as u64, path.len() as u64) }
+    pub fn pid(&self) -> u64 {
+        self.0.get()
+    }
+
+    /// Waits for the process to exit, returns how it ended
+    pub fn wait(&self) -> SyscallResult<ExitReason> {
+        Self::wait_mode(self.0.get(), ProcessWaitMode::empty()).map(|x| x.1)
+    }
+
+    /// Like wait but fails with ProcessRunning instead of waiting
+    pub fn try_wait(&self) -> SyscallResult<ExitReason> {
+        Self::wait_mode(self.0.get(), ProcessWaitMode::NON_BLOCKING).map(|x| x.1)
+    }
+
+    /// Waits for any child to exit, returns its pid and how it ended
+    pub fn wait_any() -> SyscallResult<(u64, ExitReason)> {
+        Self::wait_mode(0, ProcessWaitMode::empty())
+    }
+
+    /// Like wait_any but fails with ProcessRunning instead of waiting
+    pub fn try_wait_any() -> SyscallResult<(u64, ExitReason)> {
+        Self::wait_mode(0, ProcessWaitMode::NON_BLOCKING)
+    }
+
+    fn wait_mode(pid: u64, mode: ProcessWaitMode) -> SyscallResult<(u64, ExitReason)> {
+        let (pid, tag, a, b) = unsafe { raw_process_wait(pid, mode.bits() as u64) }?;
+        let reason = ExitReason::from_raw(tag, a, b).ok_or(SyscallError::UnknownError)?;
+        Ok((pid, reason))
+    }
+
+    /// When enabled orphaned descendants are adopted by the current process instead of initproc,
+    /// it can then wait for them
+    pub fn set_subreaper(enabled: bool) -> SyscallResult<()> {
+        unsafe { raw_process_set_subreaper(enabled as u64) }
+    }
+
+    /// Faults of this process will be reported to the conversation (it must be p2p, the other side
+    /// will receive FaultMessages and must answer with FaultReplies)
+    pub fn set_fault_port(&self, port: Conversation) -> SyscallResult<()> {
+        Self::set_fault_port_of(self.0.get(), port)
+    }
+
+    /// Like set_fault_port but for the current process
+    pub fn set_my_fault_port(port: Conversation) -> SyscallResult<()> {
+        Self::set_fault_port_of(0, port)
+    }
+
+    fn set_fault_port_of(pid: u64, port: Conversation) -> SyscallResult<()> {
+        unsafe { raw_process_fault_port_set(pid, port.capability()) }?;
+        // Now it's owned by the kernel
+        port.into_capability();
+        Ok(())
+    }
+
+    pub fn clear_fault_port(&self) -> SyscallResult<()> {
+        unsafe { raw_process_fault_port_clear(self.0.get()) }
+    }
+
+    pub fn clear_my_fault_port() -> SyscallResult<()> {
+        unsafe { raw_process_fault_port_clear(0) }
+    }
+
+    /// Restricts the cpus where the process can run (bit i = logical cpu i)
+    pub fn set_affinity(&self, mask: u64) -> SyscallResult<()> {
+        unsafe { raw_process_set_affinity(self.0.get(), mask) }
+    }
+
+    pub fn set_my_affinity(mask: u64) -> SyscallResult<()> {
+        unsafe { raw_process_set_affinity(0, mask) }
+    }
+
+    /// Changes the scheduling class of the process,
+    /// making it more important requires the SchedulerPriority capability
+    pub fn set_priority(&self, priority: Priority) -> SyscallResult<()> {
+        let (class, level) = priority.into_raw();
+        unsafe { raw_process_set_priority(self.0.get(), class, level) }
+    }
+
+    pub fn set_my_priority(priority: Priority) -> SyscallResult<()> {
+        let (class, level) = priority.into_raw();
+        unsafe { raw_process_set_priority(0, class, level) }
+    }
+
+    /// Starts the program in file inside of this (empty) process, it will find argv and envp
+    /// on its initial stack (envp entries are usually "KEY=value")
+    pub fn exec(&self, file: &File, argv: &[&str], envp: &[&str]) -> SyscallResult<()> {
+        let mut argv_buffer = [ExecArg { ptr: 0, len: 0 }; EXEC_MAX_ARGS];
+        let mut envp_buffer = [ExecArg { ptr: 0, len: 0 }; EXEC_MAX_ARGS];
+        let argv = to_exec_args(argv, &mut argv_buffer)?;
+        let envp = to_exec_args(envp, &mut envp_buffer)?;
+        unsafe {
+            raw_process_exec(
+                self.0.get(), file.0.get(),
+                argv.as_ptr() as u64, argv.len() as u64,
+                envp.as_ptr() as u64, envp.len() as u64,
+            )
+        }
+    }
+
+    /// Like exec but opens the program at path first
+    pub fn exec_path(&self, path: &str, argv: &[&str], envp: &[&str]) -> SyscallResult<()> {
+        let file = File::new(path, FsOpenMode::READ)?;
+        self.exec(&file, argv, envp)
+    }
+}
+
+fn to_exec_args<'a>(strings: &[&str], buffer: &'a mut [ExecArg; EXEC_MAX_ARGS]) -> SyscallResult<&'a [ExecArg]> {
+    if strings.len() > EXEC_MAX_ARGS {
+        return Err(SyscallError::ArgumentsTooLong);
+    }
+    for (arg, s) in buffer.iter_mut().zip(strings) {
+        *arg = ExecArg { ptr: s.as_ptr() as u64, len: s.len() as u64 };
+    }
+    Ok(&buffer[..strings.len()])
+}
+
+/// Builder for ProcessSpawnExec: the child runs the program at path and only gets the capabilities
+/// and files added here (it finds the capabilities at handles 0.. in order, the files keep their descriptors)
+pub struct Command<'a> {
+    path: &'a str,
+    args: &'a [&'a str],
+    envs: &'a [&'a str],
+    capabilities: [SpawnCapability; SPAWN_MAX_CAPABILITIES],
+    capability_count: usize,
+    files: [u64; SPAWN_MAX_FILES],
+    file_count: usize,
+    // Too many capabilities or files were added, spawn will fail
+    overflow: bool,
+}
+
+impl<'a> Command<'a> {
+    pub fn new(path: &'a str) -> Self {
+        Command {
+            path,
+            args: &[],
+            envs: &[],
+            capabilities: [SpawnCapability { handle: 0, mode: 0 }; SPAWN_MAX_CAPABILITIES],
+            capability_count: 0,
+            files: [0; SPAWN_MAX_FILES],
+            file_count: 0,
+            overflow: false,
+        }
+    }
+
+    /// The whole argv of the program (argv[0] included)
+    pub fn args(&mut self, args: &'a [&'a str]) -> &mut Self {
+        self.args = args;
+        self
+    }
+
+    /// The environment of the program, usually "KEY=value" strings
+    pub fn envs(&mut self, envs: &'a [&'a str]) -> &mut Self {
+        self.envs = envs;
+        self
+    }
+
+    /// Both processes will have the capability
+    pub fn share_capability(&mut self, cap_id: u64) -> &mut Self {
+        self.add_capability(cap_id, CapabilityPassMode::Share)
+    }
+
+    /// The capability moves to the child once it's spawned
+    pub fn transfer_capability(&mut self, cap_id: u64) -> &mut Self {
+        self.add_capability(cap_id, CapabilityPassMode::Transfer)
+    }
+
+    fn add_capability(&mut self, handle: u64, mode: CapabilityPassMode) -> &mut Self {
+        match self.capabilities.get_mut(self.capability_count) {
+            Some(x) => *x = SpawnCapability { handle, mode: mode as u64 },
+            None => self.overflow = true,
+        }
+        self.capability_count += 1;
+        self
+    }
+
+    /// The child gets its own handle to file, with the same descriptor and position
+    pub fn inherit_file(&mut self, file: &File) -> &mut Self {
+        match self.files.get_mut(self.file_count) {
+            Some(x) => *x = file.0.get(),
+            None => self.overflow = true,
+        }
+        self.file_count += 1;
+        self
+    }
+
+    pub fn spawn(&self) -> SyscallResult<Process> {
+        if self.overflow {
+            return Err(SyscallError::WrongParameters);
+        }
+        let mut argv_buffer = [ExecArg { ptr: 0, len: 0 }; EXEC_MAX_ARGS];
+        let mut envp_buffer = [ExecArg { ptr: 0, len: 0 }; EXEC_MAX_ARGS];
+        let argv = to_exec_args(self.args, &mut argv_buffer)?;
+        let envp = to_exec_args(self.envs, &mut envp_buffer)?;
+        let args = SpawnExecArgs {
+            path: ExecArg { ptr: self.path.as_ptr() as u64, len: self.path.len() as u64 },
+            argv: argv.as_ptr() as u64,
+            argc: argv.len() as u64,
+            envp: envp.as_ptr() as u64,
+            envc: envp.len() as u64,
+            capabilities: self.capabilities.as_ptr() as u64,
+            capabilities_len: self.capability_count as u64,
+            files: self.files.as_ptr() as u64,
+            files_len: self.file_count as u64,
+        };
+        let pid = unsafe { raw_process_spawn_exec(&args as *const SpawnExecArgs as u64) }?;
+        let pid = NonZeroU64::new(pid).ok_or(SyscallError::UnknownError)?;
+        Ok(Process(pid))
+    }
+}
+
+/// A kernel clock, time is measured in nanoseconds
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Clock(ClockId);
+
+impl Clock {
+    /// Time since boot, it never goes back (use it to measure durations)
+    pub const MONOTONIC: Clock = Clock(ClockId::Monotonic);
+    /// Time since the unix epoch
+    pub const REALTIME: Clock = Clock(ClockId::Realtime);
+
+    pub fn id(&self) -> ClockId {
+        self.0
+    }
+
+    /// Reads the clock from the kernel data page, falling back to a syscall if the TSC is unknown
+    pub fn now(&self) -> SyscallResult<u64> {
+        let data = kernel_data();
+        if data.tsc_ticks_per_ms == 0 {
+            return self.now_syscall();
+        }
+        let tsc = unsafe { core::arch::x86_64::_rdtsc() };
+        let monotonic = (tsc as u128 * 1_000_000 / data.tsc_ticks_per_ms as u128) as u64;
+        Ok(match self.0 {
+            ClockId::Monotonic => monotonic,
+            ClockId::Realtime => monotonic + data.boot_time_ns,
+        })
+    }
+
+    /// Asks the clock to the kernel
+    pub fn now_syscall(&self) -> SyscallResult<u64> {
+        unsafe { raw_clock_get_time(self.0 as u64) }
+    }
+
+    /// Nanoseconds passed since a previous reading of this clock
+    pub fn elapsed_since(&self, start: u64) -> SyscallResult<u64> {
+        Ok(self.now()?.saturating_sub(start))
+    }
+}
+
+/// One-shot timer, backed by a capability
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Timer(u64);
+
+impl Timer {
+    /// Creates a disarmed timer
+    pub fn new() -> SyscallResult<Timer> {
+        let handle = unsafe { raw_timer_create() }?;
+        Ok(Timer(handle))
+    }
+
+    pub fn from_capability(cap_id: u64) -> Timer {
+        Timer(cap_id)
+    }
+
+    pub fn capability(&self) -> u64 {
+        self.0
+    }
+
+    /// Arms the timer to fire when the monotonic clock reaches deadline (in nanoseconds)
+    pub fn arm_at(&self, deadline: u64) -> SyscallResult<()> {
+        unsafe { raw_timer_arm(self.0, deadline, 0) }
+    }
+
+    /// Arms the timer to fire after duration nanoseconds
+    pub fn arm_after(&self, duration: u64) -> SyscallResult<()> {
+        unsafe { raw_timer_arm(self.0, duration, 1) }
+    }
+
+    /// Disarms the timer, waiting processes fail with TimerDisarmed
+    pub fn cancel(&self) -> SyscallResult<()> {
+        unsafe { raw_timer_cancel(self.0) }
+    }
+
+    /// Waits for the timer to fire
+    pub fn wait(&self) -> SyscallResult<()> {
+        unsafe { raw_timer_wait(self.0, TimerWaitMode::empty().bits() as u64) }
+    }
+
+    /// Like wait but fails with TimerPending instead of waiting
+    pub fn try_wait(&self) -> SyscallResult<()> {
+        unsafe { raw_timer_wait(self.0, TimerWaitMode::NON_BLOCKING.bits() as u64) }
+    }
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        unsafe { raw_capability_drop(self.0) }.expect("Cannot drop Timer capability")
+    }
+}
+
+/// A thread of the current process
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Thread(NonZeroU64);
+
+impl Thread {
+    /// Runs entry(arg) in a new thread, stack_top is the end of its stack.
+    /// Unsafe: the stack must stay valid (and unused by anyone else) until the thread exits
+    pub unsafe fn spawn(entry: extern "C" fn(u64) -> !, stack_top: *mut u8, arg: u64) -> SyscallResult<Thread> {
+        // Entry expects the stack as left by a call: aligned to 16 bytes before the return address
+        let stack = (stack_top as u64 & !0xF) - 8;
+        let tid = raw_thread_spawn(entry as u64, stack, arg)?;
+        let tid = NonZeroU64::new(tid).ok_or(SyscallError::UnknownError)?;
+        Ok(Thread(tid))
+    }
+
+    pub fn my_tid() -> SyscallResult<u64> {
+        unsafe { raw_thread_my_tid() }
+    }
+
+    pub fn tid(&self) -> u64 {
+        self.0.get()
+    }
+
+    /// Sets the FS base of the current thread (where x86_64 programs keep their thread locals)
+    pub fn set_my_fs_base(address: u64) -> SyscallResult<()> {
+        unsafe { raw_thread_set_tls_base(TlsRegister::Fs as u64, address) }
+    }
+
+    /// Sets the GS base of the current thread
+    pub fn set_my_gs_base(address: u64) -> SyscallResult<()> {
+        unsafe { raw_thread_set_tls_base(TlsRegister::Gs as u64, address) }
+    }
+
+    /// Terminates the current thread, the process keeps running until its last thread exits
+    pub fn exit(code: u64) -> ! {
+        unsafe { raw_thread_exit(code) }.unwrap();
+        loop {}
     }
 }
@@ -53,13 +53,13 @@ impl Parse for SyscallDescriptor {
 pub fn create_syscall(stream: TokenStream) -> TokenStream {
     let input = parse_macro_input!(stream as SyscallDescriptor);
 
-    if input.arg_count as usize > INPUT_NAMES.len() - 1 {
-        let s = format!("Cannot create syscall with {} arguments, the maximum is {}", input.arg_count, INPUT_NAMES.len() - 1);
+    if input.arg_count as usize > INPUT_NAMES.len() {
+        let s = format!("Cannot create syscall with {} arguments, the maximum is {}", input.arg_count, INPUT_NAMES.len());
         return quote! { compile_error!(#s); }.into()
     }
 
-    if input.ret_count as usize > OUTPUT_NAMES.len() - 1 {
-        let s = format!("Cannot create syscall with {} arguments, the maximum is {}", input.ret_count, OUTPUT_NAMES.len() - 1);
+    if input.ret_count as usize > OUTPUT_NAMES.len() {
+        let s = format!("Cannot create syscall with {} return values, the maximum is {}", input.ret_count, OUTPUT_NAMES.len());
         return quote! { compile_error!(#s); }.into()
     }
 